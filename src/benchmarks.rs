@@ -0,0 +1,106 @@
+use std::hint::black_box;
+use std::time::Instant;
+
+use crate::floodfill::departures_between;
+use crate::graph_cache::YearData;
+use crate::shared::{EdgePT, LeavingTime, SECONDS_PER_DAY};
+
+/// What departures_between should give, found by checking every departure on each of the three
+/// service days
+fn departures_between_linear(departures: &[EdgePT], from: u32, until: u32) -> Vec<EdgePT> {
+    let mut found = vec![];
+    for day in 0..3 {
+        for edge in departures {
+            // Shifted a day later than departures_between does, to stay unsigned
+            let leavetime = edge.leavetime.0 + day * SECONDS_PER_DAY;
+            if leavetime >= from + SECONDS_PER_DAY && leavetime < until + SECONDS_PER_DAY {
+                found.push(EdgePT {
+                    leavetime: LeavingTime(leavetime - SECONDS_PER_DAY),
+                    ..*edge
+                });
+            }
+        }
+    }
+    // Stable, so departures at the same time stay in day then timetable order
+    found.sort_by_key(|edge| edge.leavetime);
+    found
+}
+
+fn as_tuples(departures: impl Iterator<Item = EdgePT>) -> Vec<(u32, u16, u32, u32)> {
+    departures
+        .map(|edge| (edge.leavetime.0, edge.cost.0, edge.to.0, edge.trip.0))
+        .collect()
+}
+
+/// Compares a linear scan with departures_between, as the floodfill uses it, for an hour's
+/// departures from every stop in a year's network at a range of arrival times through the day.
+/// The last windows run past midnight, so take in the next day's early departures. Checks both
+/// give the same departures in the same order
+pub fn benchmark_departures_between(year: i32) {
+    let year_data = YearData::load(year).unwrap();
+
+    let stops: Vec<&[EdgePT]> = year_data
+        .graph_pt
+        .iter()
+        .filter(|edges| !edges.is_empty())
+        .map(|edges| &edges[..])
+        .collect();
+    let departures_count: usize = stops.iter().map(|departures| departures.len()).sum();
+    let busiest_stop_departures = stops.iter().map(|departures| departures.len()).max();
+    println!(
+        "Year {}: {} stops with {} departures between them. Busiest stop has {:?} departures",
+        year,
+        stops.len(),
+        departures_count,
+        busiest_stop_departures
+    );
+
+    // Every 7 minutes from 6am to 1am, so arrival times don't line up with round-number headways
+    let window_seconds = 3600;
+    let arrival_times: Vec<u32> = (6 * 3600..25 * 3600).step_by(7 * 60).collect();
+    assert!(arrival_times.iter().any(|time| time + window_seconds > SECONDS_PER_DAY));
+    let lookups_count = stops.len() * arrival_times.len();
+
+    for arrival_time in &arrival_times {
+        for departures in &stops {
+            let until = arrival_time + window_seconds;
+            assert_eq!(
+                as_tuples(departures_between_linear(departures, *arrival_time, until).into_iter()),
+                as_tuples(departures_between(departures, *arrival_time, until))
+            );
+        }
+    }
+
+    let now = Instant::now();
+    let mut total = 0;
+    for arrival_time in &arrival_times {
+        for departures in &stops {
+            let until = arrival_time + window_seconds;
+            total += departures_between_linear(black_box(departures), *arrival_time, until).len();
+        }
+    }
+    black_box(total);
+    let linear_elapsed = now.elapsed();
+
+    let now = Instant::now();
+    let mut total = 0;
+    for arrival_time in &arrival_times {
+        for departures in &stops {
+            let until = arrival_time + window_seconds;
+            total += departures_between(black_box(departures), *arrival_time, until).count();
+        }
+    }
+    black_box(total);
+    let across_days_elapsed = now.elapsed();
+
+    println!(
+        "{} lookups, {} departures found\tLinear scan: {:?} ({:.1}ns per lookup)\t\
+         departures_between: {:?} ({:.1}ns per lookup)",
+        lookups_count,
+        total,
+        linear_elapsed,
+        linear_elapsed.as_nanos() as f64 / lookups_count as f64,
+        across_days_elapsed,
+        across_days_elapsed.as_nanos() as f64 / lookups_count as f64,
+    );
+}
@@ -0,0 +1,115 @@
+use std::fmt;
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+
+use crate::jobs::JobStatus;
+
+/// One problem with a request, eg field "start_nodes_user_input[3]" and message "node 123456789
+/// doesn't exist in 2022's network"
+#[derive(Serialize, Clone, Debug)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> FieldError {
+        FieldError {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// Anything which stops a request from being answered. Handlers return these rather than
+/// panicking, so a bad request gets a JSON error and the worker carries on
+#[derive(Clone, Debug)]
+pub enum ConnectivityError {
+    /// Every problem found with the request, not just the first
+    InvalidRequest(Vec<FieldError>),
+    /// No serialised data for the year
+    YearNotFound(i32),
+    /// A data file is missing or can't be deserialised
+    DataFile { path: String, message: String },
+    JobNotFound(String),
+    ScenarioNotFound(String),
+    /// The job's result was asked for while it was still running, or after it was cancelled
+    JobNotFinished(JobStatus),
+    /// Stops a job's floodfills early once it's been cancelled
+    Cancelled,
+    /// A job's worker panicked, with the panic message if there was one
+    JobPanicked(String),
+}
+
+impl ConnectivityError {
+    pub fn invalid(field: impl Into<String>, message: impl Into<String>) -> ConnectivityError {
+        ConnectivityError::InvalidRequest(vec![FieldError::new(field, message)])
+    }
+}
+
+impl fmt::Display for ConnectivityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectivityError::InvalidRequest(problems) => {
+                write!(f, "Invalid request: {} problem(s)", problems.len())
+            }
+            ConnectivityError::YearNotFound(year) => write!(f, "No data for year {}", year),
+            ConnectivityError::DataFile { path, message } => {
+                write!(f, "Couldn't read {}: {}", path, message)
+            }
+            ConnectivityError::JobNotFound(id) => write!(f, "No job with ID {}", id),
+            ConnectivityError::ScenarioNotFound(id) => write!(f, "No scenario with ID {}", id),
+            ConnectivityError::JobNotFinished(status) => {
+                write!(f, "Job has no result, as its status is {:?}", status)
+            }
+            ConnectivityError::Cancelled => write!(f, "Job was cancelled"),
+            ConnectivityError::JobPanicked(message) => write!(f, "Job panicked: {}", message),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: String,
+    problems: &'a [FieldError],
+}
+
+impl ResponseError for ConnectivityError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ConnectivityError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            ConnectivityError::YearNotFound(_) => StatusCode::NOT_FOUND,
+            ConnectivityError::DataFile { .. } | ConnectivityError::JobPanicked(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            ConnectivityError::JobNotFound(_) | ConnectivityError::ScenarioNotFound(_) => {
+                StatusCode::NOT_FOUND
+            }
+            ConnectivityError::JobNotFinished(_) | ConnectivityError::Cancelled => {
+                StatusCode::CONFLICT
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        println!("Request failed: {}", self);
+        let year_problem;
+        let problems: &[FieldError] = match self {
+            ConnectivityError::InvalidRequest(problems) => problems,
+            ConnectivityError::YearNotFound(year) => {
+                year_problem = [FieldError::new(
+                    "year",
+                    format!("no serialised data for year {}", year),
+                )];
+                &year_problem
+            }
+            _ => &[],
+        };
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            error: self.to_string(),
+            problems,
+        })
+    }
+}
@@ -1,36 +1,78 @@
-use std::collections::BinaryHeap;
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap};
+use crate::errors::ConnectivityError;
+use crate::graph_overlay::GraphOverlay;
 use crate::priority_queue::PriorityQueueItem;
-use crate::shared::{Cost, EdgePT, EdgeWalk, NodeID};
+use crate::reverse_graph::ReverseGraph;
+use crate::shared::{
+    Cost, EdgePT, FloodfillOutput, FloodfillScores, LeavingTime, NodeID, TripID, SECONDS_PER_DAY,
+};
 use smallvec::SmallVec;
+use crate::travel_time_relationships::TravelTimeRelationships;
+
+/// How the fastest path found to a node arrives there
+#[derive(Clone, Copy)]
+pub enum Predecessor {
+    Start,
+    Walk { from: NodeID },
+    PT { from: NodeID, leavetime: LeavingTime, trip: TripID },
+}
+
+/// Optionally filled in by get_travel_times, to reconstruct the fastest path to each node reached.
+/// A map rather than a vec per node, as a floodfill only reaches a small part of the graph
+#[derive(Default)]
+pub struct Predecessors {
+    pub reached: HashMap<u32, (Cost, Predecessor)>,
+}
+
+impl Predecessors {
+    /// Keeps the predecessor if it's the fastest way to the node found so far
+    fn offer(&mut self, node: NodeID, cost: Cost, predecessor: Predecessor) {
+        match self.reached.entry(node.0) {
+            Entry::Occupied(mut entry) => {
+                if cost < entry.get().0 {
+                    entry.insert((cost, predecessor));
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((cost, predecessor));
+            }
+        }
+    }
+}
 
 pub fn get_travel_times(
-    graph_walk: &Vec<SmallVec<[EdgeWalk; 4]>>,
-    graph_pt: &Vec<SmallVec<[EdgePT; 4]>>,
+    graph: &GraphOverlay,
     start: NodeID,
     trip_start_seconds: i32,
     init_travel_time: Cost,
-) -> (u32, Vec<u32>, Vec<u16>) {
-    
-    let time_limit: Cost = Cost(3600);
+    time_limit: Cost,
+    mut predecessors: Option<&mut Predecessors>,
+) -> Result<FloodfillOutput, ConnectivityError> {
+    check_node_exists(start, graph.node_count())?;
 
     let mut queue: BinaryHeap<PriorityQueueItem<Cost, NodeID>> = BinaryHeap::new();
     queue.push(PriorityQueueItem {
         cost: init_travel_time,
         value: start,
     });
-    let mut nodes_visited = vec![false; graph_walk.len()];
+    let mut nodes_visited = vec![false; graph.node_count()];
     //let mut nodes_visited = HashSet::new();
     let mut destination_ids: Vec<u32> = vec![];
     let mut destination_travel_times: Vec<u16> = vec![];
 
 
-    // catch where start node is over an hour from centroid
-    if init_travel_time >= Cost(3600) {
-        return (
+    // catch where start node is further from centroid than the time limit
+    if init_travel_time >= time_limit {
+        return Ok((
             start.0,
             destination_ids,
             destination_travel_times,
-        );
+        ));
+    }
+
+    if let Some(predecessors) = predecessors.as_deref_mut() {
+        predecessors.offer(start, init_travel_time, Predecessor::Start);
     }
 
     while let Some(current) = queue.pop() {
@@ -48,100 +90,520 @@ pub fn get_travel_times(
 
         // Finding adjacent walk nodes
         // skip 1st edge as it has info on whether node also has a PT service
-        for edge in &graph_walk[(current.value.0 as usize)][1..] {
-            let new_cost = Cost(current.cost.0 + edge.cost.0);
-            if new_cost < time_limit {
+        let walk_edges = graph.walk_edges(current.value.0);
+        for edge in &walk_edges[1..] {
+            // summed as u32 as time limits close to the maximum Cost could overflow
+            let new_cost = current.cost.0 as u32 + edge.cost.0 as u32;
+            if new_cost < time_limit.0 as u32 {
                 queue.push(PriorityQueueItem {
-                    cost: new_cost,
+                    cost: Cost(new_cost as u16),
                     value: edge.to,
                 });
+                if let Some(predecessors) = predecessors.as_deref_mut() {
+                    predecessors.offer(
+                        edge.to,
+                        Cost(new_cost as u16),
+                        Predecessor::Walk { from: current.value },
+                    );
+                }
             }
         }
 
         // if node has a timetable associated with it: the first value in the first 'edge'
         // will be 1 if it does, and 0 if it doesn't
-        if graph_walk[(current.value.0 as usize)][0].cost == Cost(1) {
+        if walk_edges[0].cost == Cost(1) {
             get_pt_connections(
-                &graph_pt,
+                graph,
                 current.cost.0,
                 &mut queue,
                 time_limit,
                 trip_start_seconds,
                 &current.value,
+                predecessors.as_deref_mut(),
             );
         }
 
     }
-    return (
+    Ok((
         start.0,
         destination_ids,
         destination_travel_times,
-    );
+    ))
 }
 
 
 fn get_pt_connections(
-    graph_pt: &Vec<SmallVec<[EdgePT; 4]>>,
+    graph: &GraphOverlay,
     time_so_far: u16,
     queue: &mut BinaryHeap<PriorityQueueItem<Cost, NodeID>>,
     time_limit: Cost,
     trip_start_seconds: i32,
     current_node: &NodeID,
+    predecessors: Option<&mut Predecessors>,
 ) {
     // find time node is arrived at in seconds past midnight
     let time_of_arrival_current_node = trip_start_seconds as u32 + time_so_far as u32;
+    // any service leaving at or after this can't arrive anywhere within the time limit
+    let latest_useful_leaving_time = trip_start_seconds as u32 + time_limit.0 as u32;
 
-    // find time next service leaves
-    let mut found_next_service = 0;
-    let mut journey_time: u16 = 0;
-    let mut next_leaving_time = 0;
+    // Each departure goes to its own next stop. For each next stop, take the first service to
+    // leave, plus any later ones which get there sooner (eg an express overtaking a stopping
+    // service)
+    let mut earliest_arrivals: SmallVec<[(NodeID, u32); 8]> = SmallVec::new();
+    let mut predecessors = predecessors;
 
-    for edge in &graph_pt[(current_node.0 as usize)][1..] {
-        if time_of_arrival_current_node <= edge.leavetime.0 as u32 {
-            next_leaving_time = edge.leavetime.0;
-            journey_time = edge.cost.0;
-            found_next_service = 1;
-            break;
+    let departures = departures_between(
+        graph.pt_edges(current_node.0),
+        time_of_arrival_current_node,
+        latest_useful_leaving_time,
+    );
+    for edge in departures {
+        let wait_time_this_stop = edge.leavetime.0 - time_of_arrival_current_node;
+        let arrival_time_next_stop =
+            time_so_far as u32 + wait_time_this_stop + edge.cost.0 as u32;
+        if arrival_time_next_stop >= time_limit.0 as u32 {
+            continue;
+        }
+
+        match earliest_arrivals.iter_mut().find(|(to, _)| *to == edge.to) {
+            Some((_, earliest_arrival)) => {
+                if arrival_time_next_stop >= *earliest_arrival {
+                    continue;
+                }
+                *earliest_arrival = arrival_time_next_stop;
+            }
+            None => earliest_arrivals.push((edge.to, arrival_time_next_stop)),
+        }
+
+        // add to queue
+        queue.push(PriorityQueueItem {
+            cost: Cost(arrival_time_next_stop as u16),
+            value: edge.to,
+        });
+        if let Some(predecessors) = predecessors.as_deref_mut() {
+            predecessors.offer(
+                edge.to,
+                Cost(arrival_time_next_stop as u16),
+                Predecessor::PT {
+                    from: *current_node,
+                    leavetime: edge.leavetime,
+                    trip: edge.trip,
+                },
+            );
         }
     }
+}
+
 
-    // add to queue
-    if found_next_service == 1 {
-        let wait_time_this_stop = next_leaving_time as u32 - time_of_arrival_current_node;
-        let arrival_time_next_stop =
-            time_so_far as u32 + wait_time_this_stop as u32 + journey_time as u32;
 
-        if arrival_time_next_stop < time_limit.0 as u32 {
-            //// Notice this uses 'leavingTime' from first 'edge' for the ID
-            //// of next node: this is legacy from our matrix-based approach in python
-            let destination_node = &graph_pt[(current_node.0 as usize)][0].leavetime.0;
+/// Backstop for node IDs which didn't go through validate_node_ids
+pub fn check_node_exists(node: NodeID, node_count: usize) -> Result<(), ConnectivityError> {
+    if node.0 as usize >= node_count {
+        return Err(ConnectivityError::invalid(
+            "node",
+            format!("node {} doesn't exist in a network of {} nodes", node.0, node_count),
+        ));
+    }
+    Ok(())
+}
+
 
+/// Version of get_travel_times which counts PT boardings: each boarding adds
+/// `boarding_penalty` to the cost, and no more than `max_boardings` are allowed. Travel times
+/// returned include the penalties.
+///
+/// The search is over (node, boardings so far) pairs. A node reached again with fewer boardings
+/// is explored again even though it's no faster, as it might still be able to board services
+/// the first arrival couldn't. Staying on the same vehicle through a stop isn't a new boarding:
+/// the vehicle is followed along its trip. Where trips are unknown, a departure leaving the
+/// moment a service arrives is taken to be the same vehicle
+pub fn get_travel_times_with_boardings(
+    graph: &GraphOverlay,
+    start: NodeID,
+    trip_start_seconds: i32,
+    init_travel_time: Cost,
+    time_limit: Cost,
+    max_boardings: u8,
+    boarding_penalty: Cost,
+) -> Result<FloodfillOutput, ConnectivityError> {
+    check_node_exists(start, graph.node_count())?;
+    let mut queue: BinaryHeap<PriorityQueueItem<Cost, (NodeID, u8)>> = BinaryHeap::new();
+    queue.push(PriorityQueueItem {
+        cost: init_travel_time,
+        value: (start, 0),
+    });
+    // u8::MAX until a node is reached
+    let mut fewest_boardings_settled = vec![u8::MAX; graph.node_count()];
+    let mut destination_ids: Vec<u32> = vec![];
+    let mut destination_travel_times: Vec<u16> = vec![];
+
+    if init_travel_time >= time_limit {
+        return Ok((start.0, destination_ids, destination_travel_times));
+    }
+
+    while let Some(current) = queue.pop() {
+        let (node, boardings) = current.value;
+        let fewest_boardings = &mut fewest_boardings_settled[node.0 as usize];
+        if boardings >= *fewest_boardings {
+            continue;
+        }
+        // The first time a node is settled is the fastest way there
+        if *fewest_boardings == u8::MAX {
+            destination_ids.push(node.0);
+            destination_travel_times.push(current.cost.0);
+        }
+        *fewest_boardings = boardings;
+
+        let walk_edges = graph.walk_edges(node.0);
+        for edge in &walk_edges[1..] {
+            let new_cost = current.cost.0 as u32 + edge.cost.0 as u32;
+            if new_cost < time_limit.0 as u32 {
+                queue.push(PriorityQueueItem {
+                    cost: Cost(new_cost as u16),
+                    value: (edge.to, boardings),
+                });
+            }
+        }
+
+        if walk_edges[0].cost == Cost(1) && boardings < max_boardings {
+            get_pt_connections_with_boardings(
+                graph,
+                current.cost.0,
+                &mut queue,
+                time_limit,
+                trip_start_seconds,
+                &node,
+                boardings + 1,
+                boarding_penalty,
+            );
+        }
+    }
+    Ok((start.0, destination_ids, destination_travel_times))
+}
+
+
+#[allow(clippy::too_many_arguments)]
+fn get_pt_connections_with_boardings(
+    graph: &GraphOverlay,
+    time_so_far: u16,
+    queue: &mut BinaryHeap<PriorityQueueItem<Cost, (NodeID, u8)>>,
+    time_limit: Cost,
+    trip_start_seconds: i32,
+    current_node: &NodeID,
+    boardings: u8,
+    boarding_penalty: Cost,
+) {
+    // Costs include a penalty for every boarding so far, which aren't real time: `boardings`
+    // includes this one, so time_so_far has one fewer penalty in it
+    let penalties_so_far = (boardings as u32 - 1) * boarding_penalty.0 as u32;
+    let penalties_after_boarding = boardings as u32 * boarding_penalty.0 as u32;
+    let time_of_arrival_current_node =
+        trip_start_seconds as u32 + time_so_far as u32 - penalties_so_far;
+    let latest_useful_leaving_time = trip_start_seconds as u32 + time_limit.0 as u32;
+    let mut earliest_arrivals: SmallVec<[(NodeID, u32); 8]> = SmallVec::new();
+
+    let departures = departures_between(
+        graph.pt_edges(current_node.0),
+        time_of_arrival_current_node,
+        latest_useful_leaving_time,
+    );
+    for edge in departures {
+        // costs from here on are relative to trip_start_seconds and include the penalties
+        let boarding_cost =
+            (edge.leavetime.0 - trip_start_seconds as u32) + penalties_after_boarding;
+        let arrival_time_next_stop = boarding_cost + edge.cost.0 as u32;
+        if arrival_time_next_stop >= time_limit.0 as u32 {
+            continue;
+        }
+        match earliest_arrivals.iter_mut().find(|(to, _)| *to == edge.to) {
+            Some((_, earliest_arrival)) => {
+                if arrival_time_next_stop >= *earliest_arrival {
+                    continue;
+                }
+                *earliest_arrival = arrival_time_next_stop;
+            }
+            None => earliest_arrivals.push((edge.to, arrival_time_next_stop)),
+        }
+
+        // Ride the vehicle on from stop to stop without boarding again
+        let mut riding: SmallVec<[(EdgePT, u32); 4]> = SmallVec::new();
+        riding.push((edge, boarding_cost));
+        while let Some((hop, leaving_cost)) = riding.pop() {
+            let arrival_cost = leaving_cost + hop.cost.0 as u32;
+            if arrival_cost >= time_limit.0 as u32 {
+                continue;
+            }
             queue.push(PriorityQueueItem {
-                cost: Cost(arrival_time_next_stop as u16),
-                value: NodeID(*destination_node as u32),
+                cost: Cost(arrival_cost as u16),
+                value: (hop.to, boardings),
+            });
+
+            // leavetimes are seconds past midnight, so undo the penalties to compare with them
+            let arrival_time = arrival_cost + trip_start_seconds as u32 - penalties_after_boarding;
+            let next_departures = departures_between(
+                graph.pt_edges(hop.to.0),
+                arrival_time,
+                latest_useful_leaving_time,
+            );
+            for next in next_departures {
+                let same_vehicle = if hop.trip == TripID::UNKNOWN {
+                    next.trip == TripID::UNKNOWN && next.leavetime.0 == arrival_time
+                } else {
+                    next.trip == hop.trip
+                };
+                if same_vehicle {
+                    riding.push((next, arrival_cost + (next.leavetime.0 - arrival_time)));
+                    if hop.trip != TripID::UNKNOWN {
+                        break;
+                    }
+                }
+                if hop.trip == TripID::UNKNOWN && next.leavetime.0 > arrival_time {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+
+/// Arrive-by counterpart of get_travel_times: searches backwards from a destination to find how
+/// late each node can be left while still arriving by `arrive_by_seconds`. Travel times are the
+/// gap between leaving a node and the deadline, so the latest departure from a node is
+/// `arrive_by_seconds` minus its travel time. `final_travel_time` is the time from the
+/// destination node to the destination itself
+pub fn get_travel_times_arrive_by(
+    reverse_graph: &ReverseGraph,
+    destination: NodeID,
+    arrive_by_seconds: i32,
+    final_travel_time: Cost,
+    time_limit: Cost,
+) -> Result<FloodfillOutput, ConnectivityError> {
+    check_node_exists(destination, reverse_graph.node_count())?;
+    // Nothing can leave before midnight, so no journey can take longer than the arrive-by time
+    let longest_possible = (arrive_by_seconds.max(0) as u32 + 1).min(u16::MAX as u32) as u16;
+    let time_limit = time_limit.min(Cost(longest_possible));
+
+    let mut queue: BinaryHeap<PriorityQueueItem<Cost, NodeID>> = BinaryHeap::new();
+    queue.push(PriorityQueueItem {
+        cost: final_travel_time,
+        value: destination,
+    });
+    let mut nodes_visited = vec![false; reverse_graph.node_count()];
+    let mut origin_ids: Vec<u32> = vec![];
+    let mut origin_travel_times: Vec<u16> = vec![];
+
+    if final_travel_time >= time_limit {
+        return Ok((destination.0, origin_ids, origin_travel_times));
+    }
+
+    while let Some(current) = queue.pop() {
+        if nodes_visited[current.value.0 as usize] {
+            continue;
+        }
+        origin_ids.push(current.value.0);
+        origin_travel_times.push(current.cost.0);
+        nodes_visited[current.value.0 as usize] = true;
+
+        for edge in reverse_graph.walk_edges_into(current.value.0) {
+            let new_cost = current.cost.0 as u32 + edge.cost.0 as u32;
+            if new_cost < time_limit.0 as u32 {
+                queue.push(PriorityQueueItem {
+                    cost: Cost(new_cost as u16),
+                    value: edge.to,
+                });
+            }
+        }
+
+        get_pt_connections_arrive_by(
+            reverse_graph,
+            current.cost.0,
+            &mut queue,
+            time_limit,
+            arrive_by_seconds,
+            &current.value,
+        );
+    }
+    Ok((destination.0, origin_ids, origin_travel_times))
+}
+
+
+/// Finds, for each stop with a service to this one, the latest departure which arrives here in
+/// time
+fn get_pt_connections_arrive_by(
+    reverse_graph: &ReverseGraph,
+    time_so_far: u16,
+    queue: &mut BinaryHeap<PriorityQueueItem<Cost, NodeID>>,
+    time_limit: Cost,
+    arrive_by_seconds: i32,
+    current_node: &NodeID,
+) {
+    // latest time this stop can be reached, in seconds past midnight
+    let Some(latest_arrival_current_node) = (arrive_by_seconds as u32).checked_sub(time_so_far as u32)
+    else {
+        return;
+    };
+    // any service leaving at or before this is over the time limit
+    let earliest_useful_leaving_time = (arrive_by_seconds as u32).saturating_sub(time_limit.0 as u32);
+
+    let arrivals = arrivals_between(
+        reverse_graph.pt_arrivals_into(current_node.0),
+        earliest_useful_leaving_time,
+        latest_arrival_current_node,
+    );
+
+    // Latest departure found so far from each stop, as a slower service might leave later
+    let mut latest_departures: SmallVec<[(NodeID, u32); 8]> = SmallVec::new();
+
+    for edge in arrivals {
+        if edge.leavetime.0 <= earliest_useful_leaving_time {
+            continue;
+        }
+
+        match latest_departures.iter_mut().find(|(from, _)| *from == edge.to) {
+            Some((_, latest_departure)) => {
+                if edge.leavetime.0 <= *latest_departure {
+                    continue;
+                }
+                *latest_departure = edge.leavetime.0;
+            }
+            None => latest_departures.push((edge.to, edge.leavetime.0)),
+        }
+
+        queue.push(PriorityQueueItem {
+            cost: Cost((arrive_by_seconds as u32 - edge.leavetime.0) as u16),
+            value: edge.to,
+        });
+    }
+}
+
+
+/// A stop's departures or arrivals within a time range, from the service day before, the
+/// request's service day and the one after, merged into time order. Every day is taken to run
+/// the same timetable, so a night bus timetabled at 25:30 also leaves at 01:30 on the request's
+/// day, and after midnight the next day's early departures can be caught. Times are shifted to
+/// be seconds past midnight on the request's service day
+pub struct AcrossServiceDays<'a> {
+    /// What's left of the day before's, the day's own and the day after's
+    days: [&'a [EdgePT]; 3],
+    /// Departure or arrival time, which the slices are ordered by
+    time: fn(&EdgePT) -> u32,
+    latest_first: bool,
+}
+
+impl Iterator for AcrossServiceDays<'_> {
+    type Item = EdgePT;
+
+    #[inline]
+    fn next(&mut self) -> Option<EdgePT> {
+        loop {
+            let mut next: Option<(usize, u32)> = None;
+            for (day, edges) in self.days.iter().enumerate() {
+                let edge = if self.latest_first { edges.last() } else { edges.first() };
+                if let Some(edge) = edge {
+                    // Can't go negative, as the slices only have times in range once shifted
+                    let time = (self.time)(edge) + day as u32 * SECONDS_PER_DAY - SECONDS_PER_DAY;
+                    let better = match next {
+                        None => true,
+                        Some((_, best)) if self.latest_first => time > best,
+                        Some((_, best)) => time < best,
+                    };
+                    if better {
+                        next = Some((day, time));
+                    }
+                }
+            }
+            let (day, _) = next?;
+            let edges = &mut self.days[day];
+            let edge = if self.latest_first {
+                let (edge, rest) = edges.split_last().unwrap();
+                *edges = rest;
+                edge
+            } else {
+                let (edge, rest) = edges.split_first().unwrap();
+                *edges = rest;
+                edge
+            };
+            // Arrivals are only in range by their arrival time, so one from the day before can
+            // have left before the request's day began. Times can't go below midnight, so skip it
+            let Some(leavetime) =
+                (edge.leavetime.0 + day as u32 * SECONDS_PER_DAY).checked_sub(SECONDS_PER_DAY)
+            else {
+                continue;
+            };
+            return Some(EdgePT {
+                leavetime: LeavingTime(leavetime),
+                ..*edge
             });
-        };
+        }
+    }
+}
+
+/// The part of `edges` which falls in `[from, until)` once shifted onto each service day
+fn slices_across_service_days(
+    edges: &[EdgePT],
+    from: u32,
+    until: u32,
+    time: fn(&EdgePT) -> u32,
+) -> [&[EdgePT]; 3] {
+    let mut days: [&[EdgePT]; 3] = [&[]; 3];
+    for (day, slice) in days.iter_mut().enumerate() {
+        // The times this day's timetable would need to have
+        let unshift =
+            |t: u32| (t as i64 + SECONDS_PER_DAY as i64 - day as i64 * SECONDS_PER_DAY as i64)
+                .clamp(0, u32::MAX as i64) as u32;
+        let start = edges.partition_point(|edge| time(edge) < unshift(from));
+        let end = edges.partition_point(|edge| time(edge) < unshift(until));
+        if start < end {
+            *slice = &edges[start..end];
+        }
     }
+    days
 }
 
+/// Departures leaving from `from` up to but not including `until`, earliest first. Departures
+/// must be in leaving time order
+#[inline]
+pub fn departures_between(departures: &[EdgePT], from: u32, until: u32) -> AcrossServiceDays<'_> {
+    let leavetime = |edge: &EdgePT| edge.leavetime.0;
+    AcrossServiceDays {
+        days: slices_across_service_days(departures, from, until, leavetime),
+        time: leavetime,
+        latest_first: false,
+    }
+}
+
+/// Departures arriving after `after` and by `at_or_before`, latest arrival first. Arrivals must
+/// be in arrival time order, as the reverse graph has them
+#[inline]
+pub fn arrivals_between(
+    arrivals: &[EdgePT],
+    after: u32,
+    at_or_before: u32,
+) -> AcrossServiceDays<'_> {
+    let arrival = |edge: &EdgePT| edge.leavetime.0 + edge.cost.0 as u32;
+    AcrossServiceDays {
+        days: slices_across_service_days(arrivals, after + 1, at_or_before + 1, arrival),
+        time: arrival,
+        latest_first: true,
+    }
+}
 
 
 pub fn get_all_scores_and_time_to_target_destinations(
-    travel_times: &(u32, Vec<u32>, Vec<u16>), // nodeID, destination node IDs, travel times to destinations
-    node_values_2d: &Vec<Vec<[i32; 2]>>, //&Vec<i32>,
-    travel_time_relationships: &[i32], //&Vec<i32>,
+    travel_times: &FloodfillOutput, // nodeID, destination node IDs, travel times to destinations
+    graph: &GraphOverlay, // for node values, including any new builds
+    travel_time_relationships: &TravelTimeRelationships,
     subpurpose_purpose_lookup: &[i8; 32],
-    count_original_nodes: u32,
-    node_values_padding_row_count: u32,
     target_destinations_vector: &[u32], //&Vec<u32>,
-) -> (i32, u32, [i64; 32], Vec<u32>, Vec<u16>) {
+) -> Result<FloodfillScores, ConnectivityError> {
 
-    let subpurposes_count: usize = 32;
-    let count_nodes_no_value = node_values_padding_row_count / 32;
+    let count_original_nodes = graph.node_count() as u32;
+    let count_nodes_no_value = graph.node_values_padding_row_count() / 32;
     
     // replacing set below with binary vec for faster lookup than set. Assumes only original nodes can be target destinations
     let mut target_destinations_binary_vec = vec![false; count_original_nodes as usize];
-    for id in target_destinations_vector.into_iter() {
+    for id in target_destinations_vector.iter() {
+        check_node_exists(NodeID(*id), count_original_nodes as usize)?;
         target_destinations_binary_vec[*id as usize] = true;
     }
     //let target_destinations_set: HashSet<u32> = target_destinations_vector.iter().cloned().collect();
@@ -170,10 +632,10 @@ pub fn get_all_scores_and_time_to_target_destinations(
         // if the node id is not a p2 node (ie, above count_nodes_no_value), then it will have an associated value
         if current_node <= count_original_nodes && current_node >= count_nodes_no_value {
                         
-            for subpurpose_score_pair in node_values_2d[current_node as usize].iter() {
+            for subpurpose_score_pair in graph.node_values(current_node).iter() {
                 let subpurpose_ix = subpurpose_score_pair[0];
-                let vec_start_pos_this_purpose = (subpurpose_purpose_lookup[subpurpose_ix as usize] as i32) * 3601;
-                let multiplier = travel_time_relationships[(vec_start_pos_this_purpose + current_cost as i32) as usize];
+                let purpose_ix = subpurpose_purpose_lookup[subpurpose_ix as usize] as usize;
+                let multiplier = travel_time_relationships.multiplier(purpose_ix, current_cost);
                 scores[subpurpose_ix as usize] += (subpurpose_score_pair[1] as i64) * (multiplier as i64);
             }
         }
@@ -199,13 +661,13 @@ pub fn get_all_scores_and_time_to_target_destinations(
         }
     }
     
-    return (
+    Ok((
         travel_times.1.len() as i32,
         start,
         scores,
         target_destination_ids,
         target_destination_travel_times,
-    );
+    ))
 
 }
 
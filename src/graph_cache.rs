@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use smallvec::SmallVec;
+
+use crate::raptor::Timetable;
+use crate::errors::ConnectivityError;
+use crate::read_files::{
+    read_files_parallel_excluding_node_values, read_sparse_node_values_2d_serial,
+    read_trip_service_days, year_exists,
+};
+use crate::reverse_graph::ReverseGraph;
+use crate::service_days::{DayType, ServiceDayData, TripServiceDays};
+use crate::shared::{EdgePT, EdgeWalk, GraphPT, GraphWalk, NodeValues2d};
+
+/// Everything the floodfill needs for one year's network. Never mutated once loaded: requests
+/// share it through an `Arc`
+pub struct YearData {
+    pub graph_walk: GraphWalk,
+    pub graph_pt: GraphPT,
+    pub node_values_2d: NodeValues2d,
+    pub node_values_padding_row_count: u32,
+    /// Built the first time an arrive-by query needs it
+    pub reverse_graph: Mutex<Option<Arc<ReverseGraph>>>,
+    /// Built the first time a RAPTOR query needs it
+    pub timetable: Mutex<Option<Arc<Timetable>>>,
+    /// Missing for years serialised without a service calendar, whose one timetable is taken to
+    /// be a weekday's
+    pub trip_service_days: Option<TripServiceDays>,
+    /// Departures for each day type asked for so far
+    pub service_days: Mutex<HashMap<DayType, Arc<ServiceDayData>>>,
+    /// The graphs and node values, which don't change once loaded, so are only measured once
+    loaded_size_bytes: usize,
+    /// The reverse graphs, timetables and day types' departures built for the year so far, added
+    /// as each is built. Kept apart from them so measuring never waits on a build
+    built_size_bytes: AtomicUsize,
+}
+
+impl YearData {
+    pub fn load(year: i32) -> Result<YearData, ConnectivityError> {
+        if !year_exists(year) {
+            return Err(ConnectivityError::YearNotFound(year));
+        }
+        let now = Instant::now();
+        let (graphs, node_values_2d) = rayon::join(
+            || read_files_parallel_excluding_node_values(year),
+            || read_sparse_node_values_2d_serial(year),
+        );
+        let (graph_walk, graph_pt, node_values_padding_row_count) = graphs?;
+        let node_values_2d = node_values_2d?;
+        let trip_service_days = read_trip_service_days(year)?;
+        println!("Loaded all files for year {} in {:?}", year, now.elapsed());
+        Ok(YearData::new(
+            graph_walk,
+            graph_pt,
+            node_values_2d,
+            node_values_padding_row_count,
+            trip_service_days,
+        ))
+    }
+
+    pub fn new(
+        graph_walk: GraphWalk,
+        graph_pt: GraphPT,
+        node_values_2d: NodeValues2d,
+        node_values_padding_row_count: u32,
+        trip_service_days: Option<TripServiceDays>,
+    ) -> YearData {
+        let mut loaded_size_bytes = graph_walk.len() * size_of::<SmallVec<[EdgeWalk; 4]>>()
+            + graph_pt_size_bytes(&graph_pt)
+            + node_values_2d.len() * size_of::<Vec<[i32; 2]>>();
+        for edges in graph_walk.iter().filter(|edges| edges.spilled()) {
+            loaded_size_bytes += edges.capacity() * size_of::<EdgeWalk>();
+        }
+        for values in &node_values_2d {
+            loaded_size_bytes += values.capacity() * size_of::<[i32; 2]>();
+        }
+        if let Some(trip_service_days) = &trip_service_days {
+            loaded_size_bytes += trip_service_days.0.capacity();
+        }
+        YearData {
+            graph_walk,
+            graph_pt,
+            node_values_2d,
+            node_values_padding_row_count,
+            reverse_graph: Mutex::new(None),
+            timetable: Mutex::new(None),
+            trip_service_days,
+            service_days: Mutex::new(HashMap::new()),
+            loaded_size_bytes,
+            built_size_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// The departures running on a day type, or None if that's all of them. Built the first time
+    /// each day type is asked for
+    pub fn service_day(
+        &self,
+        day_type: DayType,
+    ) -> Result<Option<Arc<ServiceDayData>>, ConnectivityError> {
+        let trip_service_days = match &self.trip_service_days {
+            Some(trip_service_days) => trip_service_days,
+            None if day_type == DayType::Weekday => return Ok(None),
+            None => {
+                return Err(ConnectivityError::invalid(
+                    "day_type",
+                    "this year only has a weekday timetable",
+                ))
+            }
+        };
+        let mut service_days = self.service_days.lock().unwrap();
+        Ok(Some(
+            service_days
+                .entry(day_type)
+                .or_insert_with(|| {
+                    let service_day =
+                        ServiceDayData::new(&self.graph_pt, trip_service_days, day_type);
+                    self.count_built(graph_pt_size_bytes(&service_day.graph_pt));
+                    Arc::new(service_day)
+                })
+                .clone(),
+        ))
+    }
+
+    /// Rough heap footprint, including the search structures built so far. Only used to decide
+    /// when to evict, so SmallVecs which have spilled onto the heap are counted at their capacity
+    /// rather than measured exactly
+    pub fn approx_size_bytes(&self) -> usize {
+        self.loaded_size_bytes + self.built_size_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Called once for each search structure built for the year or one of its day types
+    pub fn count_built(&self, size_bytes: usize) {
+        self.built_size_bytes.fetch_add(size_bytes, Ordering::Relaxed);
+    }
+}
+
+fn graph_pt_size_bytes(graph_pt: &GraphPT) -> usize {
+    let spilled_bytes: usize = graph_pt
+        .iter()
+        .filter(|edges| edges.spilled())
+        .map(|edges| edges.capacity() * size_of::<EdgePT>())
+        .sum();
+    graph_pt.len() * size_of::<SmallVec<[EdgePT; 4]>>() + spilled_bytes
+}
+
+struct YearSlot {
+    data: Mutex<Option<Arc<YearData>>>,
+    size_bytes: AtomicUsize,
+    last_used: AtomicU64,
+}
+
+/// Lazily populated, per-year store of graphs and node values.
+///
+/// Each year is loaded at most once no matter how many requests ask for it at the same time, and
+/// loading one year doesn't block requests for years which are already resident. When the total
+/// size of resident years goes over `memory_budget_bytes`, the least recently used years are
+/// dropped; requests still holding an `Arc` to an evicted year keep it alive until they finish
+pub struct GraphCache {
+    slots: Mutex<HashMap<i32, Arc<YearSlot>>>,
+    access_counter: AtomicU64,
+    memory_budget_bytes: usize,
+}
+
+impl GraphCache {
+    pub fn new(memory_budget_bytes: usize) -> GraphCache {
+        GraphCache {
+            slots: Mutex::new(HashMap::new()),
+            access_counter: AtomicU64::new(0),
+            memory_budget_bytes,
+        }
+    }
+
+    pub fn preload(&self, years: &[i32]) -> Result<(), ConnectivityError> {
+        for year in years {
+            self.get(*year)?;
+        }
+        Ok(())
+    }
+
+    /// Years which fail to load aren't kept, so they're tried again on the next request
+    pub fn get(&self, year: i32) -> Result<Arc<YearData>, ConnectivityError> {
+        let slot = {
+            let mut slots = self.slots.lock().unwrap();
+            slots
+                .entry(year)
+                .or_insert_with(|| {
+                    Arc::new(YearSlot {
+                        data: Mutex::new(None),
+                        size_bytes: AtomicUsize::new(0),
+                        last_used: AtomicU64::new(0),
+                    })
+                })
+                .clone()
+        };
+        slot.last_used.store(
+            self.access_counter.fetch_add(1, Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+
+        // Only requests for this year wait here while it loads
+        let year_data = {
+            let mut data = slot.data.lock().unwrap();
+            match &*data {
+                Some(year_data) => year_data.clone(),
+                None => {
+                    let year_data = Arc::new(YearData::load(year)?);
+                    *data = Some(year_data.clone());
+                    year_data
+                }
+            }
+        };
+
+        // Years grow as earlier requests build reverse graphs, timetables and service days
+        let size_bytes = year_data.approx_size_bytes();
+        if slot.size_bytes.swap(size_bytes, Ordering::Relaxed) < size_bytes {
+            self.evict_to_fit(year);
+        }
+        Ok(year_data)
+    }
+
+    /// Whether this copy of the year has been evicted, or replaced by a reload. A year being loaded
+    /// right now is assumed not to be, rather than waiting for it
+    pub fn is_evicted(&self, year: i32, year_data: &Arc<YearData>) -> bool {
+        let Some(slot) = self.slots.lock().unwrap().get(&year).cloned() else {
+            return true;
+        };
+        let data = match slot.data.try_lock() {
+            Ok(data) => data,
+            Err(_) => return false,
+        };
+        match &*data {
+            Some(resident) => !Arc::ptr_eq(resident, year_data),
+            None => true,
+        }
+    }
+
+    fn evict_to_fit(&self, year_to_keep: i32) {
+        // Years are picked under the slots lock but dropped after it's released, as dropping one
+        // waits on its data lock, which a request loading that year holds until it's loaded
+        let victims: Vec<Arc<YearSlot>> = {
+            let slots = self.slots.lock().unwrap();
+            // Structures built since a year was last fetched count too. Years still loading are
+            // skipped rather than waited for
+            for slot in slots.values() {
+                if let Ok(data) = slot.data.try_lock() {
+                    if let Some(year_data) = &*data {
+                        slot.size_bytes.store(year_data.approx_size_bytes(), Ordering::Relaxed);
+                    }
+                }
+            }
+            let mut total: usize = slots
+                .values()
+                .map(|slot| slot.size_bytes.load(Ordering::Relaxed))
+                .sum();
+            let mut least_recently_used: Vec<(&i32, &Arc<YearSlot>)> = slots
+                .iter()
+                .filter(|(year, slot)| {
+                    **year != year_to_keep && slot.size_bytes.load(Ordering::Relaxed) > 0
+                })
+                .collect();
+            least_recently_used.sort_by_key(|(_, slot)| slot.last_used.load(Ordering::Relaxed));
+            // If the year just loaded is over budget on its own, it's kept anyway
+            let mut victims = vec![];
+            for (year, slot) in least_recently_used {
+                if total <= self.memory_budget_bytes {
+                    break;
+                }
+                println!(
+                    "Evicting year {} from graph cache ({} of {} bytes in use)",
+                    year, total, self.memory_budget_bytes
+                );
+                total = total.saturating_sub(slot.size_bytes.swap(0, Ordering::Relaxed));
+                victims.push(slot.clone());
+            }
+            victims
+        };
+        for slot in victims {
+            *slot.data.lock().unwrap() = None;
+            slot.size_bytes.store(0, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,745 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use smallvec::SmallVec;
+
+use crate::errors::ConnectivityError;
+use crate::graph_cache::YearData;
+use crate::raptor::Timetable;
+use crate::reverse_graph::ReverseGraph;
+use crate::service_days::ServiceDayData;
+use crate::shared::{
+    pt_departures_from_legacy, Cost, EdgePT, EdgeWalk, ExtraDeparture, GraphPT, GraphWalk,
+    LeavingTime, NewRoute, NodeID, NodeValueChange, PTServiceChange, RouteStop, ScenarioEdits,
+    TripID,
+};
+
+/// One year's shared graphs and node values, with a request's or scenario's changes layered on
+/// top.
+///
+/// The base is never copied: nodes added by the request live in `added_walk`/`added_pt`, and a
+/// base node whose edges or values change gets its own copy of just that node's data in one of
+/// the `*_overrides` maps. Requests without changes get an empty overlay, so every request goes
+/// through the same code. For a day type with its own timetable, base departures come from
+/// `service_day` instead of the year's graph_pt
+pub struct GraphOverlay {
+    base: Arc<YearData>,
+    service_day: Option<Arc<ServiceDayData>>,
+    added_walk: GraphWalk,
+    added_pt: GraphPT,
+    walk_overrides: HashMap<u32, SmallVec<[EdgeWalk; 4]>>,
+    pt_overrides: HashMap<u32, SmallVec<[EdgePT; 4]>>,
+    node_values_overrides: HashMap<u32, Vec<[i32; 2]>>,
+    /// Only used with changes; otherwise the year's own are shared
+    reverse_graph: Mutex<Option<Arc<ReverseGraph>>>,
+    timetable: Mutex<Option<Arc<Timetable>>>,
+}
+
+impl GraphOverlay {
+    pub fn new(base: Arc<YearData>, service_day: Option<Arc<ServiceDayData>>) -> GraphOverlay {
+        GraphOverlay {
+            base,
+            service_day,
+            added_walk: Vec::new(),
+            added_pt: Vec::new(),
+            walk_overrides: HashMap::new(),
+            pt_overrides: HashMap::new(),
+            node_values_overrides: HashMap::new(),
+            reverse_graph: Mutex::new(None),
+            timetable: Mutex::new(None),
+        }
+    }
+
+    /// Applies the graph and new build changes from a request or scenario, in the same order the
+    /// API always has: new nodes, then extra walk edges for existing nodes, then new builds. New
+    /// routes come next, then changes to services and extra departures, then closures, so they
+    /// can take out edges the edits added. Changes to services only see the day's departures, and
+    /// trips the edits add run every day. The edits should already
+    /// have been through validate_edits and validate_edit_node_ids
+    pub fn from_edits(
+        base: Arc<YearData>,
+        service_day: Option<Arc<ServiceDayData>>,
+        input: &ScenarioEdits,
+    ) -> Result<GraphOverlay, ConnectivityError> {
+        let mut overlay = GraphOverlay::new(base, service_day);
+        let len_graph_walk = overlay.node_count();
+
+        for input_edges in input.graph_walk_additions.iter() {
+            let mut edges: SmallVec<[EdgeWalk; 4]> = SmallVec::new();
+            for array in input_edges {
+                edges.push(EdgeWalk {
+                    to: NodeID(array[1] as u32),
+                    cost: Cost(array[0] as u16),
+                });
+            }
+            overlay.added_walk.push(edges);
+        }
+
+        for input_edges in input.graph_pt_additions.iter() {
+            let mut edges = pt_departures_from_legacy(input_edges);
+            edges.sort_by_key(|edge| edge.leavetime);
+            overlay.added_pt.push(edges);
+        }
+        if overlay.added_walk.len() != input.new_nodes_count
+            || overlay.added_pt.len() != input.new_nodes_count
+        {
+            return Err(ConnectivityError::invalid(
+                "new_nodes_count",
+                "doesn't match graph_walk_additions and graph_pt_additions",
+            ));
+        }
+        debug_assert!(overlay.node_count() == len_graph_walk + input.new_nodes_count);
+
+        for (node, additions) in input
+            .graph_walk_updates_keys
+            .iter()
+            .zip(&input.graph_walk_updates_additions)
+        {
+            if *node >= overlay.node_count() {
+                return Err(ConnectivityError::invalid(
+                    "graph_walk_updates_keys",
+                    format!("node {} doesn't exist", node),
+                ));
+            }
+            let edges = overlay.walk_edges_mut(*node as u32);
+            for array in additions {
+                edges.push(EdgeWalk {
+                    to: NodeID(array[1] as u32),
+                    cost: Cost(array[0] as u16),
+                });
+            }
+        }
+
+        for (ix, new_build) in input.new_build_additions.iter().enumerate() {
+            let value_to_add = new_build[0];
+            let index_of_nearest_node = new_build[1];
+            let subpurpose_ix = new_build[2];
+            overlay.add_node_value(index_of_nearest_node as u32, subpurpose_ix, value_to_add);
+            overlay.check_node_values(
+                index_of_nearest_node as u32,
+                Some(subpurpose_ix as u8),
+                format!("new_build_additions[{}][0]", ix),
+            )?;
+        }
+        for (ix, change) in input.node_value_changes.iter().enumerate() {
+            overlay.change_node_values(change, ix)?;
+        }
+
+        // Every route's new stops are added before any are linked, as walk links can go to new
+        // stops further along the route or on later routes
+        let mut next_new_stop = overlay.node_count() as u32;
+        for route in &input.new_routes {
+            for stop in &route.stops {
+                if let RouteStop::New { .. } = stop {
+                    let mut edges: SmallVec<[EdgeWalk; 4]> = SmallVec::new();
+                    edges.push(EdgeWalk {
+                        to: NodeID(0),
+                        cost: Cost(1),
+                    });
+                    overlay.added_walk.push(edges);
+                    overlay.added_pt.push(SmallVec::new());
+                }
+            }
+        }
+        let mut next_trip = TripID(TripID::UNKNOWN.0 - 1);
+        for route in &input.new_routes {
+            overlay.add_route(route, &mut next_new_stop, &mut next_trip);
+        }
+        for change in &input.pt_service_changes {
+            overlay.change_pt_service(change, &mut next_trip);
+        }
+        overlay.add_extra_departures(&input.extra_departures, &mut next_trip)?;
+
+        for (ix, [from, to]) in input.graph_walk_removals.iter().enumerate() {
+            let edges = overlay.walk_edges_mut(*from as u32);
+            let len_before = edges.len();
+            retain_walk_edges(edges, |edge| edge.to.0 as usize != *to);
+            if edges.len() == len_before {
+                return Err(ConnectivityError::invalid(
+                    format!("graph_walk_removals[{}]", ix),
+                    format!("there's no walk edge from {} to {}", from, to),
+                ));
+            }
+        }
+
+        for removal in &input.pt_departure_removals {
+            let departures = overlay.pt_edges_mut(removal.stop as u32);
+            departures.retain(|departure| {
+                let in_range = departure.leavetime.0 >= removal.start_seconds
+                    && departure.leavetime.0 < removal.end_seconds;
+                let to_next_stop = match removal.next_stop {
+                    Some(next_stop) => departure.to.0 as usize == next_stop,
+                    None => true,
+                };
+                !(in_range && to_next_stop)
+            });
+        }
+
+        if !input.disabled_nodes.is_empty() {
+            overlay.disable_nodes(&input.disabled_nodes);
+        }
+        Ok(overlay)
+    }
+
+    /// Links any new stops, already added as nodes numbered up from `next_new_stop`, to the walk
+    /// network, marks every stop as having PT, and adds a departure from each stop but the last for
+    /// every trip. New trips are numbered down from `next_trip`, so they can't clash with a year's
+    /// own, which are numbered up from 0
+    fn add_route(&mut self, route: &NewRoute, next_new_stop: &mut u32, next_trip: &mut TripID) {
+        let mut stop_nodes: Vec<u32> = Vec::with_capacity(route.stops.len());
+        for stop in &route.stops {
+            let node = match stop {
+                RouteStop::Existing(node) => {
+                    let node = *node as u32;
+                    if let Some(pt_flag) = self.walk_edges_mut(node).first_mut() {
+                        pt_flag.cost = Cost(1);
+                    }
+                    node
+                }
+                RouteStop::New { walk_links } => {
+                    let node = *next_new_stop;
+                    *next_new_stop += 1;
+                    for [cost, other] in walk_links {
+                        self.walk_edges_mut(node).push(EdgeWalk {
+                            to: NodeID(*other as u32),
+                            cost: Cost(*cost as u16),
+                        });
+                        self.walk_edges_mut(*other as u32).push(EdgeWalk {
+                            to: NodeID(node),
+                            cost: Cost(*cost as u16),
+                        });
+                    }
+                    node
+                }
+            };
+            stop_nodes.push(node);
+        }
+
+        for first_departure in route.departure_times() {
+            let trip = *next_trip;
+            next_trip.0 -= 1;
+            let mut leavetime = first_departure;
+            for (leg, run_time) in route.run_times_seconds.iter().enumerate() {
+                let departures = self.pt_edges_mut(stop_nodes[leg]);
+                let position = departures.partition_point(|edge| edge.leavetime.0 <= leavetime);
+                departures.insert(
+                    position,
+                    EdgePT {
+                        leavetime: LeavingTime(leavetime),
+                        cost: Cost(*run_time),
+                        to: NodeID(stop_nodes[leg + 1]),
+                        trip,
+                    },
+                );
+                leavetime += *run_time as u32;
+            }
+        }
+    }
+
+    fn change_pt_service(&mut self, change: &PTServiceChange, next_trip: &mut TripID) {
+        let in_range = |leavetime: LeavingTime| {
+            leavetime.0 >= change.start_seconds.unwrap_or(0)
+                && leavetime.0 < change.end_seconds.unwrap_or(u32::MAX)
+        };
+        let mut stops: Vec<u32> = change.stops.iter().map(|stop| *stop as u32).collect();
+        stops.sort_unstable();
+        stops.dedup();
+
+        if let Some(multiplier) = change.frequency_multiplier {
+            // So a new departure gets the same trip at every stop its vehicle calls at
+            let mut new_trips: HashMap<(TripID, usize), TripID> = HashMap::new();
+            for stop in &stops {
+                let departures = self.pt_edges_mut(*stop);
+                let mut by_next_stop: BTreeMap<u32, Vec<EdgePT>> = BTreeMap::new();
+                departures.retain(|departure| {
+                    if in_range(departure.leavetime) {
+                        by_next_stop.entry(departure.to.0).or_default().push(*departure);
+                        return false;
+                    }
+                    true
+                });
+                for group in by_next_stop.values() {
+                    departures.extend(respace_departures(
+                        group,
+                        multiplier,
+                        &mut new_trips,
+                        next_trip,
+                    ));
+                }
+                departures.sort_by_key(|departure| departure.leavetime);
+            }
+        }
+
+        if let Some(multiplier) = change.run_time_multiplier {
+            let mut selected: Vec<(u32, usize, EdgePT)> = vec![];
+            for stop in &stops {
+                for (ix, departure) in self.pt_edges(*stop).iter().enumerate() {
+                    if in_range(departure.leavetime) {
+                        selected.push((*stop, ix, *departure));
+                    }
+                }
+            }
+            // Earlier departures first, so a vehicle's shift is known before its next departure
+            selected.sort_by_key(|(_, _, departure)| departure.leavetime);
+
+            // How far each vehicle's next departure moves. Keyed by trip and the stop it calls at
+            // next, or without trip IDs, by that stop and when the vehicle gets there
+            let mut trip_shifts: HashMap<(TripID, u32), i64> = HashMap::new();
+            let mut arrival_shifts: HashMap<(u32, u32), i64> = HashMap::new();
+            for (stop, ix, departure) in selected {
+                let shift = if departure.trip == TripID::UNKNOWN {
+                    arrival_shifts.remove(&(stop, departure.leavetime.0))
+                } else {
+                    trip_shifts.remove(&(departure.trip, stop))
+                }
+                .unwrap_or(0);
+                let cost = ((departure.cost.0 as f64 * multiplier).round() as i64)
+                    .clamp(1, u16::MAX as i64);
+                let next_shift = shift + cost - departure.cost.0 as i64;
+                if departure.trip == TripID::UNKNOWN {
+                    arrival_shifts.insert(
+                        (departure.to.0, departure.leavetime.0 + departure.cost.0 as u32),
+                        next_shift,
+                    );
+                } else {
+                    trip_shifts.insert((departure.trip, departure.to.0), next_shift);
+                }
+                self.pt_edges_mut(stop)[ix] = EdgePT {
+                    leavetime: LeavingTime((departure.leavetime.0 as i64 + shift).max(0) as u32),
+                    cost: Cost(cost as u16),
+                    ..departure
+                };
+            }
+            for stop in &stops {
+                self.pt_edges_mut(*stop)
+                    .sort_by_key(|departure| departure.leavetime);
+            }
+        }
+    }
+
+    fn add_extra_departures(
+        &mut self,
+        extras: &[ExtraDeparture],
+        next_trip: &mut TripID,
+    ) -> Result<(), ConnectivityError> {
+        let mut order: Vec<usize> = (0..extras.len()).collect();
+        order.sort_by_key(|ix| extras[*ix].leave_seconds);
+        // Trips of the extra departures by the stop they arrive at and when
+        let mut arrivals: HashMap<(u32, u32), TripID> = HashMap::new();
+        for ix in order {
+            let extra = &extras[ix];
+            let (stop, next_stop) = (extra.stop as u32, extra.next_stop as u32);
+            let run_time = match extra.run_time_seconds {
+                Some(run_time) => Cost(run_time),
+                None => self
+                    .pt_edges(stop)
+                    .iter()
+                    .filter(|departure| departure.to.0 == next_stop)
+                    .min_by_key(|departure| {
+                        (departure.leavetime.0 as i64 - extra.leave_seconds as i64).abs()
+                    })
+                    .map(|departure| departure.cost)
+                    .ok_or_else(|| {
+                        ConnectivityError::invalid(
+                            format!("extra_departures[{}].run_time_seconds", ix),
+                            format!(
+                                "is needed, as there are no departures from {} to {} to copy",
+                                stop, next_stop
+                            ),
+                        )
+                    })?,
+            };
+            let trip = arrivals
+                .remove(&(stop, extra.leave_seconds))
+                .unwrap_or_else(|| {
+                    let trip = *next_trip;
+                    next_trip.0 -= 1;
+                    trip
+                });
+            arrivals.insert((next_stop, extra.leave_seconds + run_time.0 as u32), trip);
+
+            if let Some(pt_flag) = self.walk_edges_mut(stop).first_mut() {
+                pt_flag.cost = Cost(1);
+            }
+            let departures = self.pt_edges_mut(stop);
+            let position =
+                departures.partition_point(|edge| edge.leavetime.0 <= extra.leave_seconds);
+            departures.insert(
+                position,
+                EdgePT {
+                    leavetime: LeavingTime(extra.leave_seconds),
+                    cost: run_time,
+                    to: NodeID(next_stop),
+                    trip,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Takes out every walk edge and departure to or from the nodes. Edges into them come from
+    /// the shared reverse graph, plus any node these edits added or changed, so only the nodes
+    /// losing edges are copied
+    fn disable_nodes(&mut self, nodes: &[usize]) {
+        let now = Instant::now();
+        let disabled: HashSet<u32> = nodes.iter().map(|node| *node as u32).collect();
+        let reverse_graph = self.shared_reverse_graph();
+        let mut edited: HashSet<u32> = (reverse_graph.node_count() as u32..self.node_count() as u32)
+            .chain(self.walk_overrides.keys().copied())
+            .chain(self.pt_overrides.keys().copied())
+            .collect();
+        for node in &disabled {
+            if (*node as usize) < reverse_graph.node_count() {
+                let walk_from = reverse_graph.walk_edges_into(*node).iter();
+                let pt_from = reverse_graph.pt_arrivals_into(*node).iter();
+                edited.extend(walk_from.map(|edge| edge.to.0));
+                edited.extend(pt_from.map(|edge| edge.to.0));
+            }
+        }
+
+        for node in &disabled {
+            let edges = self.walk_edges_mut(*node);
+            edges.truncate(1);
+            // No PT from here any more
+            if let Some(pt_flag) = edges.first_mut() {
+                pt_flag.cost = Cost(0);
+            }
+            self.pt_edges_mut(*node).clear();
+        }
+        for node in edited.difference(&disabled) {
+            let walk_edges = self.walk_edges(*node);
+            if walk_edges.iter().skip(1).any(|edge| disabled.contains(&edge.to.0)) {
+                retain_walk_edges(self.walk_edges_mut(*node), |edge| {
+                    !disabled.contains(&edge.to.0)
+                });
+            }
+            if self.pt_edges(*node).iter().any(|edge| disabled.contains(&edge.to.0)) {
+                self.pt_edges_mut(*node)
+                    .retain(|edge| !disabled.contains(&edge.to.0));
+            }
+        }
+        println!("Disabled {} nodes in {:?}", disabled.len(), now.elapsed());
+    }
+
+    pub fn base(&self) -> &Arc<YearData> {
+        &self.base
+    }
+
+    /// Node count including any nodes added by this request
+    pub fn node_count(&self) -> usize {
+        self.base.graph_walk.len() + self.added_walk.len()
+    }
+
+    pub fn has_changes(&self) -> bool {
+        !self.added_walk.is_empty()
+            || !self.added_pt.is_empty()
+            || !self.walk_overrides.is_empty()
+            || !self.pt_overrides.is_empty()
+            || !self.node_values_overrides.is_empty()
+    }
+
+    /// The transposed graph for arrive-by queries. Without changes, this year's (or this day
+    /// type's) is built once and shared; with changes it's built once for this overlay, which for
+    /// a stored scenario means once for every request using it
+    pub fn reverse_graph(&self) -> Arc<ReverseGraph> {
+        if !self.has_changes() {
+            return self.shared_reverse_graph();
+        }
+        let mut reverse_graph = self.reverse_graph.lock().unwrap();
+        reverse_graph
+            .get_or_insert_with(|| {
+                let now = Instant::now();
+                let built = Arc::new(ReverseGraph::from_graph(self));
+                println!("Built reverse graph in {:?}", now.elapsed());
+                built
+            })
+            .clone()
+    }
+
+    /// The year's (or day type's) reverse graph without this overlay's changes
+    fn shared_reverse_graph(&self) -> Arc<ReverseGraph> {
+        let cache = match &self.service_day {
+            Some(service_day) => &service_day.reverse_graph,
+            None => &self.base.reverse_graph,
+        };
+        let mut reverse_graph = cache.lock().unwrap();
+        reverse_graph
+            .get_or_insert_with(|| {
+                let now = Instant::now();
+                let unchanged = GraphOverlay::new(self.base.clone(), self.service_day.clone());
+                let built = Arc::new(ReverseGraph::from_graph(&unchanged));
+                println!("Built reverse graph in {:?}", now.elapsed());
+                // An overlay's own is dropped with it, so only shared ones count towards the year
+                self.base.count_built(built.approx_size_bytes());
+                built
+            })
+            .clone()
+    }
+
+    /// Routes and trips for RAPTOR queries, cached the same way as the reverse graph
+    pub fn timetable(&self) -> Arc<Timetable> {
+        let cache = match &self.service_day {
+            _ if self.has_changes() => &self.timetable,
+            Some(service_day) => &service_day.timetable,
+            None => &self.base.timetable,
+        };
+        let mut timetable = cache.lock().unwrap();
+        timetable
+            .get_or_insert_with(|| {
+                let now = Instant::now();
+                let built = Arc::new(Timetable::from_graph(self));
+                println!("Built RAPTOR timetable in {:?}", now.elapsed());
+                if !self.has_changes() {
+                    self.base.count_built(built.approx_size_bytes());
+                }
+                built
+            })
+            .clone()
+    }
+
+    pub fn node_values_padding_row_count(&self) -> u32 {
+        self.base.node_values_padding_row_count
+    }
+
+    /// First edge is the PT flag, as in the base graph
+    #[inline]
+    pub fn walk_edges(&self, node: u32) -> &[EdgeWalk] {
+        // Checking for emptiness first keeps hashing off the hot path for unchanged graphs
+        if !self.walk_overrides.is_empty() {
+            if let Some(edges) = self.walk_overrides.get(&node) {
+                return edges;
+            }
+        }
+        let base_len = self.base.graph_walk.len();
+        if (node as usize) < base_len {
+            &self.base.graph_walk[node as usize]
+        } else {
+            &self.added_walk[node as usize - base_len]
+        }
+    }
+
+    /// Departures from a stop, ordered by leaving time
+    #[inline]
+    pub fn pt_edges(&self, node: u32) -> &[EdgePT] {
+        if !self.pt_overrides.is_empty() {
+            if let Some(edges) = self.pt_overrides.get(&node) {
+                return edges;
+            }
+        }
+        let base_pt = match &self.service_day {
+            Some(service_day) => &service_day.graph_pt,
+            None => &self.base.graph_pt,
+        };
+        if (node as usize) < base_pt.len() {
+            &base_pt[node as usize]
+        } else {
+            &self.added_pt[node as usize - base_pt.len()]
+        }
+    }
+
+    /// [subpurpose index, value] pairs for a node. Empty for nodes added by this request unless a
+    /// new build was placed on them
+    pub fn node_values(&self, node: u32) -> &[[i32; 2]] {
+        if !self.node_values_overrides.is_empty() {
+            if let Some(values) = self.node_values_overrides.get(&node) {
+                return values;
+            }
+        }
+        match self.base.node_values_2d.get(node as usize) {
+            Some(values) => values,
+            None => &[],
+        }
+    }
+
+    /// Copies a node's walk edges into the overlay the first time it's changed
+    fn walk_edges_mut(&mut self, node: u32) -> &mut SmallVec<[EdgeWalk; 4]> {
+        let base_len = self.base.graph_walk.len();
+        if node as usize >= base_len {
+            return &mut self.added_walk[node as usize - base_len];
+        }
+        let base = &self.base;
+        self.walk_overrides
+            .entry(node)
+            .or_insert_with(|| base.graph_walk[node as usize].clone())
+    }
+
+    /// Copies a node's departures into the overlay the first time they're changed, from the same
+    /// graph pt_edges reads
+    fn pt_edges_mut(&mut self, node: u32) -> &mut SmallVec<[EdgePT; 4]> {
+        let base_pt = match &self.service_day {
+            Some(service_day) => &service_day.graph_pt,
+            None => &self.base.graph_pt,
+        };
+        if node as usize >= base_pt.len() {
+            return &mut self.added_pt[node as usize - base_pt.len()];
+        }
+        self.pt_overrides
+            .entry(node)
+            .or_insert_with(|| base_pt[node as usize].clone())
+    }
+
+    fn node_values_mut(&mut self, node: u32) -> &mut Vec<[i32; 2]> {
+        let base = &self.base;
+        self.node_values_overrides.entry(node).or_insert_with(|| {
+            base.node_values_2d
+                .get(node as usize)
+                .cloned()
+                .unwrap_or_default()
+        })
+    }
+
+    fn change_node_values(
+        &mut self,
+        change: &NodeValueChange,
+        ix: usize,
+    ) -> Result<(), ConnectivityError> {
+        let subpurpose = change.subpurpose();
+        let matches = |pair: &[i32; 2]| match subpurpose {
+            Some(subpurpose) => pair[0] == subpurpose as i32,
+            None => true,
+        };
+        let node = change.node() as u32;
+        // Scoring skips the nodes at the start, which are PT stops
+        let count_nodes_no_value = self.node_values_padding_row_count() / 32;
+        let (receiving_node, receiving_field) = match change {
+            NodeValueChange::Relocate { to_node, .. } => (*to_node as u32, "to_node"),
+            _ => (node, "node"),
+        };
+        let adds_values = !matches!(change, NodeValueChange::Remove { .. });
+        if adds_values && receiving_node < count_nodes_no_value {
+            return Err(ConnectivityError::invalid(
+                format!("node_value_changes[{}].{}", ix, receiving_field),
+                format!("node {} is a PT stop, which can't have values", receiving_node),
+            ));
+        }
+
+        match change {
+            NodeValueChange::Set { subpurpose, value, .. } => {
+                let values = self.node_values_mut(node);
+                values.retain(|pair| pair[0] != *subpurpose as i32);
+                values.push([*subpurpose as i32, *value]);
+            }
+            NodeValueChange::Scale { multiplier, .. } => {
+                for pair in self.node_values_mut(node).iter_mut().filter(|pair| matches(pair)) {
+                    pair[1] = (pair[1] as f64 * multiplier).round() as i32;
+                }
+            }
+            NodeValueChange::Remove { .. } => {
+                self.node_values_mut(node).retain(|pair| !matches(pair));
+            }
+            NodeValueChange::Relocate { to_node, .. } => {
+                let values = self.node_values_mut(node);
+                let moved: Vec<[i32; 2]> = values.iter().copied().filter(matches).collect();
+                values.retain(|pair| !matches(pair));
+                for [subpurpose_ix, value] in moved {
+                    self.add_node_value(*to_node as u32, subpurpose_ix, value);
+                }
+            }
+        }
+        self.check_node_values(
+            receiving_node,
+            subpurpose,
+            format!("node_value_changes[{}]", ix),
+        )
+    }
+
+    /// Errors if a change has left the node with a negative value for the subpurpose, or for any
+    /// subpurpose if None
+    fn check_node_values(
+        &self,
+        node: u32,
+        subpurpose: Option<u8>,
+        field: String,
+    ) -> Result<(), ConnectivityError> {
+        for [subpurpose_ix, value] in self.node_values(node) {
+            let checked = match subpurpose {
+                Some(subpurpose) => *subpurpose_ix == subpurpose as i32,
+                None => true,
+            };
+            if checked && *value < 0 {
+                return Err(ConnectivityError::invalid(
+                    field,
+                    format!(
+                        "would leave node {} with a value of {} for subpurpose {}",
+                        node, value, subpurpose_ix
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds to the node's value for the subpurpose if it has one, otherwise appends a new value
+    fn add_node_value(&mut self, node: u32, subpurpose_ix: i32, value_to_add: i32) {
+        let values = self.node_values_mut(node);
+        let mut found_existing_subpurpose = false;
+        for subpurpose_score_pair in values.iter_mut() {
+            if subpurpose_score_pair[0] == subpurpose_ix {
+                subpurpose_score_pair[1] += value_to_add;
+                found_existing_subpurpose = true;
+            }
+        }
+        if !found_existing_subpurpose {
+            values.push([subpurpose_ix, value_to_add]);
+        }
+    }
+}
+
+/// Like retain, but always keeps the first edge, which is the PT flag
+fn retain_walk_edges(edges: &mut SmallVec<[EdgeWalk; 4]>, keep: impl Fn(&EdgeWalk) -> bool) {
+    let mut ix = 0;
+    edges.retain(|edge| {
+        ix += 1;
+        ix == 1 || keep(edge)
+    });
+}
+
+/// Spreads `multiplier` times as many departures evenly from the first of `group` to the last,
+/// where `group` is a stop's departures to one next stop, in order. Each new one copies the
+/// journey time of the departure before it. Departures which land on an existing one keep its
+/// trip, and the rest get new trips from `new_trips`, keyed by the trip before and how far along
+/// the gap they are
+fn respace_departures(
+    group: &[EdgePT],
+    multiplier: f64,
+    new_trips: &mut HashMap<(TripID, usize), TripID>,
+    next_trip: &mut TripID,
+) -> Vec<EdgePT> {
+    if group.len() < 2 {
+        return group.to_vec();
+    }
+    let gaps = group.len() - 1;
+    let new_gaps = ((gaps as f64 * multiplier).round() as usize).max(1);
+    (0..=new_gaps)
+        .map(|ix| {
+            // Position in `group` is ix * gaps / new_gaps, kept as a fraction so departures
+            // which land on an existing one are spotted exactly
+            let before = ix * gaps / new_gaps;
+            let remainder = ix * gaps % new_gaps;
+            let departure = group[before];
+            if remainder == 0 {
+                return departure;
+            }
+            let gap = group[before + 1].leavetime.0 - departure.leavetime.0;
+            let trip = if departure.trip == TripID::UNKNOWN {
+                TripID::UNKNOWN
+            } else {
+                *new_trips
+                    .entry((departure.trip, remainder))
+                    .or_insert_with(|| {
+                        let trip = *next_trip;
+                        next_trip.0 -= 1;
+                        trip
+                    })
+            };
+            EdgePT {
+                leavetime: LeavingTime(
+                    departure.leavetime.0 + (gap as usize * remainder / new_gaps) as u32,
+                ),
+                trip,
+                ..departure
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,600 @@
+use std::collections::HashMap;
+use std::io::BufWriter;
+use std::time::Instant;
+
+use fs_err::File;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use smallvec::SmallVec;
+
+use crate::read_files::deserialize_bincoded_file;
+use crate::shared::{Cost, EdgePT, EdgeWalk, GraphPT, GraphWalk, LeavingTime, NodeID, TripID};
+
+/// Stops further than this from every node are left out, along with their departures
+const MAX_SNAP_METRES: f64 = 250.0;
+
+#[derive(Deserialize)]
+struct GtfsStop {
+    stop_id: String,
+    stop_lat: Option<f64>,
+    stop_lon: Option<f64>,
+    /// Empty or 0 for somewhere to board. Stations, entrances and the like have no departures
+    #[serde(default)]
+    location_type: Option<u8>,
+}
+
+#[derive(Deserialize)]
+struct GtfsRoute {
+    route_id: String,
+    route_type: u16,
+}
+
+#[derive(Deserialize)]
+struct GtfsTrip {
+    route_id: String,
+    service_id: String,
+    trip_id: String,
+}
+
+#[derive(Deserialize)]
+struct GtfsStopTime {
+    trip_id: String,
+    /// Empty between timepoints, which are interpolated
+    arrival_time: String,
+    departure_time: String,
+    stop_id: String,
+    stop_sequence: u32,
+}
+
+/// One stop on one trip
+struct Call {
+    stop_sequence: u32,
+    arrival: Option<u32>,
+    departure: Option<u32>,
+    /// None if the stop wasn't snapped
+    node: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct GtfsCalendar {
+    service_id: String,
+    monday: u8,
+    tuesday: u8,
+    wednesday: u8,
+    thursday: u8,
+    friday: u8,
+    saturday: u8,
+    sunday: u8,
+}
+
+#[derive(Deserialize)]
+struct GtfsCalendarDate {
+    service_id: String,
+    date: String,
+    exception_type: u8,
+}
+
+#[derive(Deserialize)]
+struct GtfsTransfer {
+    from_stop_id: String,
+    to_stop_id: String,
+    #[serde(default)]
+    transfer_type: Option<u8>,
+    #[serde(default)]
+    min_transfer_time: Option<u32>,
+}
+
+/// Builds the year's PT graph from `data/gtfs_{year}.zip`, replacing whatever serialise_files
+/// wrote. Run it after serialise_files, as it needs the walk graph and
+/// `data/node_coordinates_{year}.json`: the [longitude, latitude] of every node, by node ID.
+///
+/// Each GTFS stop is snapped to the nearest node, or the nearest of the PT stop nodes at the
+/// start of the graph if it has any, and every trip's stop to stop hops become departures with
+/// the trip's ID. Trip IDs are the trips' order in trips.txt. Also writes the trips' service
+/// days from calendar.txt (or calendar_dates.txt, for services only listed there), sets the
+/// walk graph's PT flags to match the new departures, and adds walk edges for transfers.txt
+/// transfers with a minimum time between stops snapped to different nodes
+pub fn import_gtfs(year: i32) {
+    let now = Instant::now();
+    let path = format!("data/gtfs_{}.zip", year);
+    let mut archive = zip::ZipArchive::new(File::open(&path).unwrap()).unwrap();
+    println!("Importing GTFS from {}", path);
+
+    let mut graph_walk: GraphWalk =
+        deserialize_bincoded_file(&format!("p1_main_nodes_vector_6am_{year}")).unwrap();
+    let node_values_padding_row_count: u32 =
+        deserialize_bincoded_file(&format!("node_values_padding_row_count_6am_{year}")).unwrap();
+    let coordinates_path = format!("data/node_coordinates_{}.json", year);
+    let coordinates: Vec<[f64; 2]> =
+        serde_json::from_str(&fs_err::read_to_string(&coordinates_path).unwrap()).unwrap();
+    assert!(
+        coordinates.len() == graph_walk.len(),
+        "{} has {} nodes but the walk graph has {}",
+        coordinates_path,
+        coordinates.len(),
+        graph_walk.len()
+    );
+
+    // Snap stops to the PT stop nodes if the network has them, so they don't end up on nodes
+    // with values
+    let stop_nodes_count = (node_values_padding_row_count / 32) as usize;
+    let candidates = if stop_nodes_count > 0 {
+        &coordinates[..stop_nodes_count]
+    } else {
+        &coordinates[..]
+    };
+    let locator = NodeLocator::new(candidates);
+    let stops: Vec<GtfsStop> = read_table(&mut archive, "stops.txt").unwrap();
+    let mut node_by_stop: HashMap<String, u32> = HashMap::new();
+    let mut stops_not_snapped = 0;
+    for stop in &stops {
+        if !matches!(stop.location_type, None | Some(0)) {
+            continue;
+        }
+        let (Some(lat), Some(lon)) = (stop.stop_lat, stop.stop_lon) else {
+            stops_not_snapped += 1;
+            continue;
+        };
+        match locator.nearest([lon, lat]) {
+            Some(node) => {
+                node_by_stop.insert(stop.stop_id.clone(), node);
+            }
+            None => stops_not_snapped += 1,
+        }
+    }
+    println!(
+        "Snapped {} stops to nodes; {} were over {}m from any node or had no location",
+        node_by_stop.len(),
+        stops_not_snapped,
+        MAX_SNAP_METRES
+    );
+
+    let service_days = read_service_days(&mut archive);
+    let route_types: HashMap<String, u16> = read_table::<GtfsRoute>(&mut archive, "routes.txt")
+        .unwrap()
+        .into_iter()
+        .map(|route| (route.route_id, route.route_type))
+        .collect();
+    let trips: Vec<GtfsTrip> = read_table(&mut archive, "trips.txt").unwrap();
+    let trip_ix_by_id: HashMap<&str, u32> = trips
+        .iter()
+        .enumerate()
+        .map(|(ix, trip)| (trip.trip_id.as_str(), ix as u32))
+        .collect();
+    // Services missing from both calendar files are taken to run every day
+    let trip_service_days: Vec<u8> = trips
+        .iter()
+        .map(|trip| *service_days.get(&trip.service_id).unwrap_or(&0b1111111))
+        .collect();
+
+    let mut calls_by_trip: HashMap<u32, Vec<Call>> = HashMap::new();
+    for stop_time in read_table::<GtfsStopTime>(&mut archive, "stop_times.txt").unwrap() {
+        let Some(trip_ix) = trip_ix_by_id.get(stop_time.trip_id.as_str()) else {
+            continue;
+        };
+        calls_by_trip.entry(*trip_ix).or_default().push(Call {
+            stop_sequence: stop_time.stop_sequence,
+            arrival: parse_gtfs_time(&stop_time.arrival_time),
+            departure: parse_gtfs_time(&stop_time.departure_time),
+            node: node_by_stop.get(&stop_time.stop_id).copied(),
+        });
+    }
+
+    let mut graph_pt: GraphPT = vec![SmallVec::new(); graph_walk.len()];
+    let mut departures_by_route_type: HashMap<u16, usize> = HashMap::new();
+    let mut hops_too_long = 0;
+    for (trip_ix, mut calls) in calls_by_trip {
+        calls.sort_unstable_by_key(|call| call.stop_sequence);
+        let times = interpolate_times(&calls);
+        let route_type = route_types.get(&trips[trip_ix as usize].route_id).copied();
+
+        // (node, arrival, departure) at each stop which was snapped. Stops which weren't are
+        // passed through, and consecutive stops snapped to the same node are one stop as far
+        // as the graph is concerned
+        let mut stops: Vec<(u32, u32, u32)> = vec![];
+        for (call, times) in calls.iter().zip(times) {
+            let (Some(node), Some((arrival, departure))) = (call.node, times) else {
+                continue;
+            };
+            match stops.last_mut() {
+                Some(last) if last.0 == node => last.2 = departure,
+                _ => stops.push((node, arrival, departure)),
+            }
+        }
+        for pair in stops.windows(2) {
+            let (from, _, leavetime) = pair[0];
+            let (to, arrival, _) = pair[1];
+            let Ok(cost) = u16::try_from(arrival.saturating_sub(leavetime)) else {
+                hops_too_long += 1;
+                continue;
+            };
+            graph_pt[from as usize].push(EdgePT {
+                leavetime: LeavingTime(leavetime),
+                cost: Cost(cost),
+                to: NodeID(to),
+                trip: TripID(trip_ix),
+            });
+            if let Some(route_type) = route_type {
+                *departures_by_route_type.entry(route_type).or_default() += 1;
+            }
+        }
+    }
+    for edges in graph_pt.iter_mut() {
+        edges.sort_by_key(|edge| edge.leavetime);
+    }
+    println!(
+        "Departures by GTFS route type: {:?}; {} hops took longer than {} seconds and were left \
+         out",
+        departures_by_route_type,
+        hops_too_long,
+        u16::MAX
+    );
+
+    // The floodfill only boards at nodes with the PT flag set
+    for (node, edges) in graph_walk.iter_mut().enumerate() {
+        edges[0].cost = Cost(if graph_pt[node].is_empty() { 0 } else { 1 });
+    }
+    let transfers_added = add_transfers(&mut archive, &node_by_stop, &mut graph_walk);
+    println!("Added {} walk edges for transfers", transfers_added);
+
+    write_bincode(&format!("p1_main_nodes_vector_6am_{year}"), &graph_walk);
+    write_bincode(&format!("p2_departures_vector_6am_{year}"), &graph_pt);
+    write_bincode(&format!("trip_service_days_6am_{year}"), &trip_service_days);
+    println!("GTFS import for year {} took {:?}", year, now.elapsed());
+}
+
+/// Reads a whole table from the feed. None if the feed doesn't have it
+fn read_table<T: DeserializeOwned>(
+    archive: &mut zip::ZipArchive<File>,
+    filename: &str,
+) -> Option<Vec<T>> {
+    let file = match archive.by_name(filename) {
+        Ok(file) => file,
+        Err(zip::result::ZipError::FileNotFound) => return None,
+        Err(err) => panic!("Couldn't read {} from the GTFS zip: {}", filename, err),
+    };
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(file);
+    let rows = reader
+        .deserialize()
+        .collect::<Result<Vec<T>, _>>()
+        .unwrap_or_else(|err| panic!("Couldn't parse {}: {}", filename, err));
+    println!("Read {} rows from {}", rows.len(), filename);
+    Some(rows)
+}
+
+/// Day mask for each service, with bit 0 for Monday up to bit 6 for Sunday
+fn read_service_days(archive: &mut zip::ZipArchive<File>) -> HashMap<String, u8> {
+    let calendars = read_table::<GtfsCalendar>(archive, "calendar.txt").unwrap_or_default();
+    let dates = read_table::<GtfsCalendarDate>(archive, "calendar_dates.txt").unwrap_or_default();
+    service_days_from_tables(calendars, dates)
+}
+
+/// Services only in calendar_dates.txt run on the days of the week they're added on. A day mask
+/// repeats every week, so it can't take out single dates: removals (`exception_type` 2), and
+/// dates added to services already in calendar.txt, are counted and left out with a warning
+fn service_days_from_tables(
+    calendars: Vec<GtfsCalendar>,
+    dates: Vec<GtfsCalendarDate>,
+) -> HashMap<String, u8> {
+    let mut service_days: HashMap<String, u8> = HashMap::new();
+    for calendar in calendars {
+        let days = [
+            calendar.monday,
+            calendar.tuesday,
+            calendar.wednesday,
+            calendar.thursday,
+            calendar.friday,
+            calendar.saturday,
+            calendar.sunday,
+        ];
+        let mask = days
+            .iter()
+            .enumerate()
+            .filter(|(_, runs)| **runs == 1)
+            .fold(0, |mask, (day, _)| mask | 1 << day);
+        service_days.insert(calendar.service_id, mask);
+    }
+    let mut added_days: HashMap<String, u8> = HashMap::new();
+    let mut ignored = 0;
+    for date in dates {
+        if date.exception_type != 1 || service_days.contains_key(&date.service_id) {
+            ignored += 1;
+            continue;
+        }
+        if let Some(day) = day_of_week(&date.date) {
+            *added_days.entry(date.service_id).or_default() |= 1 << day;
+        }
+    }
+    if ignored > 0 {
+        println!(
+            "Warning: ignored {} calendar_dates.txt exceptions to calendar.txt services, as \
+             service days are weekly",
+            ignored
+        );
+    }
+    service_days.extend(added_days);
+    service_days
+}
+
+/// Seconds past midnight from a GTFS `H:MM:SS` time, which can go past 24:00
+fn parse_gtfs_time(time: &str) -> Option<u32> {
+    let mut parts = time.split(':').map(|part| part.parse::<u32>().ok());
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Some(hours)), Some(Some(minutes)), Some(Some(seconds)), None) => {
+            Some(hours * 3600 + minutes * 60 + seconds)
+        }
+        _ => None,
+    }
+}
+
+/// 0 for Monday up to 6 for Sunday, from a GTFS `YYYYMMDD` date
+fn day_of_week(date: &str) -> Option<u32> {
+    if date.len() != 8 {
+        return None;
+    }
+    let year: i64 = date[..4].parse().ok()?;
+    let month: i64 = date[4..6].parse().ok()?;
+    let day: i64 = date[6..].parse().ok()?;
+    // Days since 1970-01-01, which was a Thursday
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    Some((days + 3).rem_euclid(7) as u32)
+}
+
+/// (arrival, departure) at each call. Calls without times, between timepoints, get times spread
+/// evenly between the timepoints either side. None before the first or after the last timepoint
+fn interpolate_times(calls: &[Call]) -> Vec<Option<(u32, u32)>> {
+    let mut times: Vec<Option<(u32, u32)>> = calls
+        .iter()
+        .map(|call| match (call.arrival, call.departure) {
+            (Some(arrival), Some(departure)) => Some((arrival, departure)),
+            (Some(time), None) | (None, Some(time)) => Some((time, time)),
+            (None, None) => None,
+        })
+        .collect();
+    let timepoints: Vec<usize> = (0..times.len()).filter(|ix| times[*ix].is_some()).collect();
+    for pair in timepoints.windows(2) {
+        let (start, end) = (pair[0], pair[1]);
+        let leaving = times[start].unwrap().1;
+        let arriving = times[end].unwrap().0.max(leaving);
+        for (ix, time) in times.iter_mut().enumerate().take(end).skip(start + 1) {
+            let interpolated =
+                leaving + (arriving - leaving) * (ix - start) as u32 / (end - start) as u32;
+            *time = Some((interpolated, interpolated));
+        }
+    }
+    times
+}
+
+/// Walk edges for transfers with a minimum time, between stops on different nodes. Returns how
+/// many were added
+fn add_transfers(
+    archive: &mut zip::ZipArchive<File>,
+    node_by_stop: &HashMap<String, u32>,
+    graph_walk: &mut GraphWalk,
+) -> usize {
+    let mut added = 0;
+    for transfer in read_table::<GtfsTransfer>(archive, "transfers.txt").unwrap_or_default() {
+        if transfer.transfer_type != Some(2) {
+            continue;
+        }
+        let (Some(from), Some(to), Some(min_transfer_time)) = (
+            node_by_stop.get(&transfer.from_stop_id),
+            node_by_stop.get(&transfer.to_stop_id),
+            transfer.min_transfer_time,
+        ) else {
+            continue;
+        };
+        let edges = &mut graph_walk[*from as usize];
+        if from == to || edges[1..].iter().any(|edge| edge.to.0 == *to) {
+            continue;
+        }
+        edges.push(EdgeWalk {
+            to: NodeID(*to),
+            cost: Cost(min_transfer_time.min(u16::MAX as u32) as u16),
+        });
+        added += 1;
+    }
+    added
+}
+
+pub fn write_bincode<T: serde::Serialize>(filename: &str, value: &T) {
+    let path = format!("serialised_data/{}.bin", filename);
+    let file = BufWriter::new(File::create(&path).unwrap());
+    bincode::serialize_into(file, value).unwrap();
+    println!("Serialised to {}", path);
+}
+
+/// Finds the nearest node to a point, using a grid of cells about MAX_SNAP_METRES across
+struct NodeLocator<'a> {
+    coordinates: &'a [[f64; 2]],
+    cells: HashMap<(i64, i64), Vec<u32>>,
+    cell_degrees: f64,
+}
+
+impl<'a> NodeLocator<'a> {
+    fn new(coordinates: &'a [[f64; 2]]) -> NodeLocator<'a> {
+        let cell_degrees = MAX_SNAP_METRES / METRES_PER_DEGREE;
+        let mut cells: HashMap<(i64, i64), Vec<u32>> = HashMap::new();
+        for (node, point) in coordinates.iter().enumerate() {
+            cells
+                .entry(cell_of(*point, cell_degrees))
+                .or_default()
+                .push(node as u32);
+        }
+        NodeLocator {
+            coordinates,
+            cells,
+            cell_degrees,
+        }
+    }
+
+    /// None if there's no node within MAX_SNAP_METRES
+    fn nearest(&self, point: [f64; 2]) -> Option<u32> {
+        let (x, y) = cell_of(point, self.cell_degrees);
+        // Cells shrink east to west away from the equator, so look further along longitude
+        let cells_across = (1.0 / point[1].to_radians().cos()).ceil().min(100.0) as i64;
+        let mut nearest: Option<(f64, u32)> = None;
+        for cell_x in x - cells_across..=x + cells_across {
+            for cell_y in y - 1..=y + 1 {
+                for node in self.cells.get(&(cell_x, cell_y)).into_iter().flatten() {
+                    let distance = distance_metres(point, self.coordinates[*node as usize]);
+                    let nearer = match nearest {
+                        Some((nearest_distance, _)) => distance < nearest_distance,
+                        None => true,
+                    };
+                    if distance <= MAX_SNAP_METRES && nearer {
+                        nearest = Some((distance, *node));
+                    }
+                }
+            }
+        }
+        nearest.map(|(_, node)| node)
+    }
+}
+
+const METRES_PER_DEGREE: f64 = 111_320.0;
+
+fn cell_of(point: [f64; 2], cell_degrees: f64) -> (i64, i64) {
+    (
+        (point[0] / cell_degrees).floor() as i64,
+        (point[1] / cell_degrees).floor() as i64,
+    )
+}
+
+/// Equirectangular approximation, which is plenty over a few hundred metres
+fn distance_metres(a: [f64; 2], b: [f64; 2]) -> f64 {
+    let mean_latitude = ((a[1] + b[1]) / 2.0).to_radians();
+    let dx = (a[0] - b[0]) * mean_latitude.cos();
+    let dy = a[1] - b[1];
+    (dx * dx + dy * dy).sqrt() * METRES_PER_DEGREE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(arrival: Option<u32>, departure: Option<u32>) -> Call {
+        Call {
+            stop_sequence: 0,
+            arrival,
+            departure,
+            node: None,
+        }
+    }
+
+    #[test]
+    fn parses_gtfs_times_past_midnight() {
+        assert_eq!(parse_gtfs_time("8:05:30"), Some(29130));
+        assert_eq!(parse_gtfs_time("08:05:30"), Some(29130));
+        assert_eq!(parse_gtfs_time("25:10:00"), Some(90600));
+        assert_eq!(parse_gtfs_time(""), None);
+        assert_eq!(parse_gtfs_time("08:05"), None);
+        assert_eq!(parse_gtfs_time("08:05:30:00"), None);
+        assert_eq!(parse_gtfs_time("8:xx:30"), None);
+    }
+
+    #[test]
+    fn finds_day_of_week() {
+        assert_eq!(day_of_week("19700101"), Some(3));
+        // Either side of a leap day, and across the new year
+        assert_eq!(day_of_week("20240228"), Some(2));
+        assert_eq!(day_of_week("20240229"), Some(3));
+        assert_eq!(day_of_week("20240301"), Some(4));
+        assert_eq!(day_of_week("20231231"), Some(6));
+        assert_eq!(day_of_week("20240101"), Some(0));
+        assert_eq!(day_of_week("2024011"), None);
+        assert_eq!(day_of_week("2024xx01"), None);
+    }
+
+    #[test]
+    fn interpolates_between_timepoints() {
+        let calls = [
+            call(None, None),
+            call(Some(100), Some(120)),
+            call(None, None),
+            call(None, None),
+            call(Some(210), None),
+            call(None, None),
+        ];
+        assert_eq!(
+            interpolate_times(&calls),
+            vec![
+                None,
+                Some((100, 120)),
+                Some((150, 150)),
+                Some((180, 180)),
+                Some((210, 210)),
+                None,
+            ]
+        );
+        // A timepoint arriving before the last one left doesn't go back in time
+        let calls = [call(Some(100), Some(100)), call(None, None), call(Some(50), None)];
+        assert_eq!(
+            interpolate_times(&calls),
+            vec![Some((100, 100)), Some((100, 100)), Some((50, 50))]
+        );
+    }
+
+    #[test]
+    fn locates_nearest_node_within_snap_distance() {
+        let metres = |metres: f64| metres / METRES_PER_DEGREE;
+        // Degrees of longitude are shorter away from the equator
+        let east = |metres: f64| [metres / METRES_PER_DEGREE / 51.5f64.to_radians().cos(), 51.5];
+        let coordinates = [
+            [0.0, 51.5],
+            east(150.0),
+            east(5000.0),
+            [0.0, 51.5 + metres(400.0)],
+        ];
+        let locator = NodeLocator::new(&coordinates);
+        assert_eq!(locator.nearest(east(10.0)), Some(0));
+        assert_eq!(locator.nearest(east(100.0)), Some(1));
+        assert_eq!(locator.nearest(east(5100.0)), Some(2));
+        assert_eq!(locator.nearest([0.0, 51.5 + metres(300.0)]), Some(3));
+        assert_eq!(locator.nearest(east(2000.0)), None);
+        assert_eq!(locator.nearest([90.0, 0.0]), None);
+    }
+
+    #[test]
+    fn leaves_out_calendar_date_removals() {
+        let calendar = |service_id: &str, weekdays: u8| GtfsCalendar {
+            service_id: service_id.to_string(),
+            monday: weekdays,
+            tuesday: weekdays,
+            wednesday: weekdays,
+            thursday: weekdays,
+            friday: weekdays,
+            saturday: 1 - weekdays,
+            sunday: 1 - weekdays,
+        };
+        let date = |service_id: &str, date: &str, exception_type: u8| GtfsCalendarDate {
+            service_id: service_id.to_string(),
+            date: date.to_string(),
+            exception_type,
+        };
+        let service_days = service_days_from_tables(
+            vec![calendar("weekdays", 1), calendar("weekends", 0)],
+            vec![
+                // A Monday bank holiday
+                date("weekdays", "20240506", 2),
+                date("weekends", "20240506", 1),
+                // Saturday and Sunday only services
+                date("extra", "20240504", 1),
+                date("extra", "20240505", 1),
+                date("removed", "20240505", 2),
+            ],
+        );
+        assert_eq!(service_days["weekdays"], 31);
+        assert_eq!(service_days["weekends"], 96);
+        assert_eq!(service_days["extra"], 96);
+        assert!(!service_days.contains_key("removed"));
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::errors::ConnectivityError;
+
+/// Shared between a job's worker and the API. The floodfill adds to `completed` after each
+/// start node, and stops early once `cancelled` is set
+pub struct JobProgress {
+    pub completed: AtomicUsize,
+    pub total: usize,
+    pub cancelled: AtomicBool,
+}
+
+impl JobProgress {
+    pub fn new(total: usize) -> JobProgress {
+        JobProgress {
+            completed: AtomicUsize::new(0),
+            total,
+            cancelled: AtomicBool::new(false),
+        }
+    }
+
+    /// Called from inside the rayon loops, once per start node
+    pub fn start_node_done(&self) -> Result<(), ConnectivityError> {
+        self.completed.fetch_add(1, Ordering::Relaxed);
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(ConnectivityError::Cancelled);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+enum JobOutcome {
+    Running,
+    InMemory(String),
+    /// Results over the spill threshold are written to the results directory
+    Spilled(PathBuf),
+    Failed(ConnectivityError),
+    Cancelled,
+}
+
+pub struct Job {
+    pub progress: JobProgress,
+    outcome: Mutex<JobOutcome>,
+    finished_at: Mutex<Option<Instant>>,
+}
+
+/// What GET /jobs/{id} returns
+#[derive(Serialize)]
+pub struct JobStatusResponse {
+    pub id: String,
+    pub status: JobStatus,
+    pub completed_start_nodes: usize,
+    pub total_start_nodes: usize,
+    pub error: Option<String>,
+}
+
+/// Jobs by ID. Finished jobs are kept for `retention` after they finish, then dropped the next
+/// time any job is submitted or looked up
+pub struct JobStore {
+    jobs: Mutex<HashMap<String, Arc<Job>>>,
+    results_dir: PathBuf,
+    spill_threshold_bytes: usize,
+    retention: Duration,
+}
+
+impl JobStore {
+    pub fn new(results_dir: PathBuf, spill_threshold_bytes: usize, retention: Duration) -> JobStore {
+        JobStore {
+            jobs: Mutex::new(HashMap::new()),
+            results_dir,
+            spill_threshold_bytes,
+            retention,
+        }
+    }
+
+    /// Registers a job and runs `work` on its own thread, so the rayon pool does the floodfills
+    /// and no actix worker is blocked. Returns the job ID
+    pub fn submit<F>(self: &Arc<Self>, total_start_nodes: usize, work: F) -> String
+    where
+        F: FnOnce(&JobProgress) -> Result<String, ConnectivityError> + Send + 'static,
+    {
+        self.drop_expired();
+        let id = uuid::Uuid::new_v4().to_string();
+        let job = Arc::new(Job {
+            progress: JobProgress::new(total_start_nodes),
+            outcome: Mutex::new(JobOutcome::Running),
+            finished_at: Mutex::new(None),
+        });
+        self.jobs.lock().unwrap().insert(id.clone(), job.clone());
+
+        let store = self.clone();
+        let job_id = id.clone();
+        std::thread::spawn(move || {
+            let now = Instant::now();
+            // Otherwise a panic in the floodfills would leave the job running forever
+            let result = catch_unwind(AssertUnwindSafe(|| work(&job.progress)))
+                .unwrap_or_else(|panic| Err(ConnectivityError::JobPanicked(panic_message(&panic))));
+            // delete() sets cancelled before it takes this lock to remove any spilled file, so
+            // checking under the lock means a result is never spilled after it's been deleted
+            let mut outcome = job.outcome.lock().unwrap();
+            *outcome = match result {
+                Ok(_) if job.progress.cancelled.load(Ordering::Relaxed) => JobOutcome::Cancelled,
+                Ok(result) => store.keep_result(&job_id, result),
+                Err(ConnectivityError::Cancelled) => JobOutcome::Cancelled,
+                Err(err) => JobOutcome::Failed(err),
+            };
+            drop(outcome);
+            println!("Job {} finished in {:?}", job_id, now.elapsed());
+            *job.finished_at.lock().unwrap() = Some(Instant::now());
+        });
+        id
+    }
+
+    fn keep_result(&self, id: &str, result: String) -> JobOutcome {
+        if result.len() <= self.spill_threshold_bytes {
+            return JobOutcome::InMemory(result);
+        }
+        let path = self.results_dir.join(format!("{}.json", id));
+        let written = std::fs::create_dir_all(&self.results_dir)
+            .and_then(|_| std::fs::write(&path, &result));
+        match written {
+            Ok(()) => JobOutcome::Spilled(path),
+            Err(err) => {
+                // Better to hold a big result in memory than lose it
+                println!("Couldn't spill job {} to {:?}, keeping it in memory: {}", id, path, err);
+                JobOutcome::InMemory(result)
+            }
+        }
+    }
+
+    fn get(&self, id: &str) -> Result<Arc<Job>, ConnectivityError> {
+        self.drop_expired();
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| ConnectivityError::JobNotFound(id.to_string()))
+    }
+
+    pub fn status(&self, id: &str) -> Result<JobStatusResponse, ConnectivityError> {
+        let job = self.get(id)?;
+        let outcome = job.outcome.lock().unwrap();
+        let (status, error) = match &*outcome {
+            JobOutcome::Running => (JobStatus::Running, None),
+            JobOutcome::InMemory(_) | JobOutcome::Spilled(_) => (JobStatus::Done, None),
+            JobOutcome::Failed(err) => (JobStatus::Failed, Some(err.to_string())),
+            JobOutcome::Cancelled => (JobStatus::Cancelled, None),
+        };
+        Ok(JobStatusResponse {
+            id: id.to_string(),
+            status,
+            completed_start_nodes: job.progress.completed.load(Ordering::Relaxed),
+            total_start_nodes: job.progress.total,
+            error,
+        })
+    }
+
+    /// The result body, or the job's own error if it failed
+    pub fn result(&self, id: &str) -> Result<String, ConnectivityError> {
+        let job = self.get(id)?;
+        let outcome = job.outcome.lock().unwrap();
+        match &*outcome {
+            JobOutcome::InMemory(result) => Ok(result.clone()),
+            JobOutcome::Spilled(path) => {
+                std::fs::read_to_string(path).map_err(|err| ConnectivityError::DataFile {
+                    path: path.display().to_string(),
+                    message: err.to_string(),
+                })
+            }
+            JobOutcome::Running => Err(ConnectivityError::JobNotFinished(JobStatus::Running)),
+            JobOutcome::Cancelled => Err(ConnectivityError::JobNotFinished(JobStatus::Cancelled)),
+            JobOutcome::Failed(err) => Err(err.clone()),
+        }
+    }
+
+    /// Cancels the job if it's still running, and forgets it along with any spilled result
+    pub fn delete(&self, id: &str) -> Result<JobStatusResponse, ConnectivityError> {
+        let mut status = self.status(id)?;
+        if let Some(job) = self.jobs.lock().unwrap().remove(id) {
+            job.progress.cancelled.store(true, Ordering::Relaxed);
+            if let JobOutcome::Spilled(path) = &*job.outcome.lock().unwrap() {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        if status.status == JobStatus::Running {
+            status.status = JobStatus::Cancelled;
+        }
+        Ok(status)
+    }
+
+    fn drop_expired(&self) {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, job| {
+            let finished_at = *job.finished_at.lock().unwrap();
+            let expired = matches!(finished_at, Some(at) if at.elapsed() > self.retention);
+            if expired {
+                if let JobOutcome::Spilled(path) = &*job.outcome.lock().unwrap() {
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+            !expired
+        });
+    }
+}
+
+fn panic_message(panic: &Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "no message".to_string()
+    }
+}
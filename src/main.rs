@@ -1,308 +1,768 @@
-use actix_web::{get, post, web, App, HttpServer};
+use actix_web::{delete, get, post, web, App, Either, HttpResponse, HttpServer};
 use rayon::prelude::*;
-use smallvec::SmallVec;
-use std::time::Instant;
+use std::borrow::Cow;
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 
-use crate::shared::{Cost, EdgePT, EdgeWalk, LeavingTime, NodeID, UserInputJSON};
-use floodfill::{get_travel_times, get_all_scores_and_time_to_target_destinations};
-use get_time_of_day_index::get_time_of_day_index;
+use crate::shared::{
+    ArriveByResult, CompareInputJSON, CompareResponse, ComparisonResult, Cost, FloodfillMode,
+    FloodfillOutput, FloodfillResponse, FloodfillResult, FloodfillScores, NodeID, Scenario,
+    ScenarioComparison, UserInputJSON, COMPARE_RESPONSE_VERSION,
+};
+use floodfill::{
+    get_all_scores_and_time_to_target_destinations, get_travel_times, get_travel_times_arrive_by,
+    get_travel_times_with_boardings, Predecessors,
+};
+use errors::ConnectivityError;
+use graph_cache::{GraphCache, YearData};
+use graph_overlay::GraphOverlay;
+use jobs::{JobProgress, JobStore};
+use profile::{summarise_profile, ProfileResult};
+use raptor::{get_travel_times_raptor, get_travel_times_raptor_profile, Engine, Timetable};
+use scenarios::ScenarioStore;
+use reconstruct_route::{reconstruct_route, StartNodeRoutes};
+use time_bands::{TimeBands, TimeBandsManifest};
+use travel_time_relationships::TravelTimeRelationships;
+use validation::{
+    validate_base_start_nodes, validate_compare_input, validate_node_ids, validate_route_input,
+    validate_user_input,
+};
 use read_files::{
-    read_files_parallel_excluding_node_values,
     read_small_files_serial,
     deserialize_bincoded_file,
     create_graph_walk_len,
-    read_sparse_node_values_2d_serial,
 };
 
+mod benchmarks;
+mod errors;
 mod floodfill;
-mod get_time_of_day_index;
+mod graph_cache;
+mod graph_overlay;
+mod gtfs_import;
+mod jobs;
+mod osm_import;
+mod osm_pbf;
 mod priority_queue;
+mod profile;
+mod raptor;
 mod read_files;
+mod reconstruct_route;
+mod reverse_graph;
+mod scenarios;
 mod serialise_files;
+mod service_days;
 mod shared;
+mod time_bands;
+mod travel_time_relationships;
+mod validation;
 
 struct AppState {
-    travel_time_relationships_all: Vec<Vec<i32>>,
+    time_bands: TimeBands,
     subpurpose_purpose_lookup: [i8; 32],
+    graph_cache: GraphCache,
+    job_store: Arc<JobStore>,
+    scenario_store: ScenarioStore,
+}
+
+impl AppState {
+    /// Fetching a year can evict others, so scenarios built on them are let go too
+    fn year_data(&self, year: i32) -> Result<Arc<YearData>, ConnectivityError> {
+        let year_data = self.graph_cache.get(year)?;
+        self.scenario_store.drop_evicted(&self.graph_cache);
+        Ok(year_data)
+    }
+
+    /// Decay curves for the time of day's band. They stop at an hour, so longer cutoffs need them
+    /// extending
+    fn get_travel_time_relationships(
+        &self,
+        trip_start_seconds: i32,
+        input: &UserInputJSON,
+    ) -> Cow<'_, TravelTimeRelationships> {
+        let travel_time_relationships =
+            self.time_bands.travel_time_relationships(trip_start_seconds);
+        match travel_time_relationships
+            .extended_to(input.max_travel_time_seconds, input.decay_curve_extension)
+        {
+            Some(extended) => Cow::Owned(extended),
+            None => travel_time_relationships,
+        }
+    }
 }
 
 #[get("/")]
 async fn index() -> String {
-    format!("App is listening")
+    "App is listening".to_string()
 }
 
 #[get("/get_node_id_count/")]
-async fn get_node_id_count() -> String {
+async fn get_node_id_count() -> Result<String, ConnectivityError> {
     //let count_original_nodes = data.graph_walk_len;
     let year: i32 = 2022;   //// TODO change this dynamically depending on when user hits this api... OR drop this from Rust api and store in py
-    let graph_walk_len: i32 = deserialize_bincoded_file(&format!("graph_walk_len_{year}"));
-    return serde_json::to_string(&graph_walk_len).unwrap();
+    let graph_walk_len: i32 = deserialize_bincoded_file(&format!("graph_walk_len_{year}"))?;
+    Ok(serde_json::to_string(&graph_walk_len).unwrap())
 }
 
-#[post("/floodfill_pt/")]
-async fn floodfill_pt(data: web::Data<AppState>, input: web::Json<UserInputJSON>) -> String {
-    if input.year < 2022 {
-        assert!(input.graph_walk_additions.is_empty());
+// Validates the request, then layers its changes over the cached graphs for its year, or uses
+// its scenario's overlay
+fn graph_for_request(
+    data: &AppState,
+    input: &UserInputJSON,
+) -> Result<Arc<GraphOverlay>, ConnectivityError> {
+    validate_user_input(input)?;
+    if let Some(scenario_id) = &input.scenario_id {
+        return graph_for_scenario(data, input, scenario_id);
     }
- 
-    if input.graph_walk_additions.is_empty()
-        && input.graph_pt_additions.is_empty()
-        && input.graph_walk_updates_keys.is_empty()
-        && input.graph_walk_updates_additions.is_empty()
-        && input.new_build_additions.is_empty()
-    {
-        return floodfill_pt_no_changes(data, input);
+    let year_data = data.year_data(input.year)?;
+    validate_node_ids(input, year_data.graph_walk.len(), 0)?;
+    let service_day = year_data.service_day(input.day_type)?;
+    Ok(Arc::new(GraphOverlay::from_edits(year_data, service_day, &input.edits)?))
+}
+
+// A stored scenario's overlay, once the request's year and node IDs are checked against it
+fn graph_for_scenario(
+    data: &AppState,
+    input: &UserInputJSON,
+    scenario_id: &str,
+) -> Result<Arc<GraphOverlay>, ConnectivityError> {
+    let scenario = data
+        .scenario_store
+        .get(scenario_id, input.day_type, &data.graph_cache)?;
+    if scenario.year != input.year {
+        return Err(ConnectivityError::invalid(
+            "year",
+            format!("scenario {} is for {}", scenario_id, scenario.year),
+        ));
     }
-    
-    println!("Floodfill request received, with changes to the graphs");
-
-    // Read in files
-    let (mut graph_walk, mut graph_pt, node_values_padding_row_count) =
-        read_files_parallel_excluding_node_values(input.year);
-
-    let len_graph_walk = graph_walk.len();
-    let len_graph_pt = graph_pt.len();
-    assert!(len_graph_pt == len_graph_walk);
-
-    for input_edges in input.graph_walk_additions.iter() {
-        let mut edges: SmallVec<[EdgeWalk; 4]> = SmallVec::new();
-        for array in input_edges {
-            edges.push(EdgeWalk {
-                to: NodeID(array[1] as u32),
-                cost: Cost(array[0] as u16),
-            });
-        }
-        graph_walk.push(edges);
+    validate_node_ids(
+        input,
+        scenario.overlay.base().graph_walk.len(),
+        scenario.new_nodes_count,
+    )?;
+    Ok(scenario.overlay.clone())
+}
+
+/// Stores a base year and edits to it, in the same fields as /floodfill_pt/, for requests to
+/// refer to by scenario_id
+#[post("/scenarios/")]
+async fn create_scenario(
+    data: web::Data<AppState>,
+    input: web::Json<Scenario>,
+) -> Result<HttpResponse, ConnectivityError> {
+    let year = input.year;
+    let id = data
+        .scenario_store
+        .create(input.into_inner(), &data.graph_cache)?;
+    println!("Scenario {} created for {}", id, year);
+    Ok(HttpResponse::Created().json(serde_json::json!({ "scenario_id": id, "year": year })))
+}
+
+#[post("/floodfill_pt/")]
+async fn floodfill_pt(
+    data: web::Data<AppState>,
+    input: web::Json<UserInputJSON>,
+) -> Result<Either<HttpResponse, String>, ConnectivityError> {
+    if input.stream {
+        return Ok(Either::Left(floodfill_pt_stream(data, input.into_inner())?));
     }
+    Ok(Either::Right(run_floodfill_pt(&data, &input, None)?))
+}
 
-    for input_edges in input.graph_pt_additions.iter() {
-        let mut edges: SmallVec<[EdgePT; 4]> = SmallVec::new();
-        for array in input_edges {
-            edges.push(EdgePT {
-                leavetime: LeavingTime(array[0] as u32),
-                cost: Cost(array[1] as u16),
+// One JSON line per start node, sent a chunk at a time as each chunk's floodfills and scores are
+// done rather than all at the end, so the whole response is never held in memory. Lines come in
+// the same order as start_nodes_user_input. Anything wrong with the request is still caught
+// before streaming starts; an error part way through ends the stream with an {"error": ...} line
+fn floodfill_pt_stream(
+    data: web::Data<AppState>,
+    input: UserInputJSON,
+) -> Result<HttpResponse, ConnectivityError> {
+    let graph = graph_for_request(&data, &input)?;
+    println!(
+        "Streaming floodfill request received\tNodes count: {}",
+        input.start_nodes_user_input.len()
+    );
+    // Bounded, so a slow client holds up the floodfills rather than lines piling up in memory
+    let (sender, receiver) = mpsc::channel::<web::Bytes>(256);
+
+    std::thread::spawn(move || {
+        let now = Instant::now();
+        let timetable = get_timetable_if_needed(&graph, &input);
+        let travel_time_relationships =
+            data.get_travel_time_relationships(input.trip_start_seconds, &input);
+        let line_for_start_node = |i: usize| {
+            let line = get_travel_times_for_start_node(
+                &graph,
+                timetable.as_deref(),
+                &input,
+                i,
+                input.trip_start_seconds,
+            )
+            .and_then(|travel_times| {
+                get_all_scores_and_time_to_target_destinations(
+                    &travel_times,
+                    &graph,
+                    &travel_time_relationships,
+                    &data.subpurpose_purpose_lookup,
+                    &input.target_destinations,
+                )
             });
+            let (mut line, failed) = match line {
+                Ok(scores) if input.legacy_output => {
+                    (serde_json::to_string(&scores).unwrap(), false)
+                }
+                Ok(scores) => {
+                    let result =
+                        FloodfillResult::from_scores(&scores, &data.subpurpose_purpose_lookup);
+                    (serde_json::to_string(&result).unwrap(), false)
+                }
+                Err(err) => (
+                    serde_json::json!({ "error": err.to_string() }).to_string(),
+                    true,
+                ),
+            };
+            line.push('\n');
+            (line, failed)
+        };
+
+        // Start nodes are done a chunk at a time and sent from this thread, as waiting on the
+        // client from inside rayon would hold up every other request's floodfills too
+        let start_nodes_count = input.start_nodes_user_input.len();
+        let chunk_size = rayon::current_num_threads() * 4;
+        let mut streamed = Ok(());
+        'chunks: for chunk_start in (0..start_nodes_count).step_by(chunk_size) {
+            let lines: Vec<(String, bool)> = (chunk_start
+                ..(chunk_start + chunk_size).min(start_nodes_count))
+                .into_par_iter()
+                .map(line_for_start_node)
+                .collect();
+            for (line, failed) in lines {
+                // Fails if the client has gone
+                if sender.blocking_send(web::Bytes::from(line)).is_err() || failed {
+                    streamed = Err(());
+                    break 'chunks;
+                }
+            }
         }
-        graph_pt.push(edges);
-    }
-    assert!(graph_walk.len() == len_graph_walk + input.new_nodes_count);
-    assert!(graph_pt.len() == len_graph_pt + input.new_nodes_count);
-
-    for i in 0..input.graph_walk_updates_keys.len() {
-        let node = input.graph_walk_updates_keys[i];
-
-        // TODO Just modify in-place
-        let mut edges: SmallVec<[EdgeWalk; 4]> = graph_walk[node].clone();
-        for array in &input.graph_walk_updates_additions[i] {
-            edges.push(EdgeWalk {
-                to: NodeID(array[1] as u32),
-                cost: Cost(array[0] as u16),
-            });
+        match streamed {
+            Ok(()) => println!("Streaming floodfill took {:?}", now.elapsed()),
+            Err(()) => println!("Streaming floodfill stopped early after {:?}", now.elapsed()),
         }
-        graph_walk[node] = edges;
+    });
+
+    let body = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver
+            .recv()
+            .await
+            .map(|line| (Ok::<_, Infallible>(line), receiver))
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(body))
+}
+
+// Shared by /floodfill_pt/ and jobs. Jobs pass their progress, to be updated as each start node
+// finishes and to stop early if they're cancelled
+fn run_floodfill_pt(
+    data: &AppState,
+    input: &UserInputJSON,
+    progress: Option<&JobProgress>,
+) -> Result<String, ConnectivityError> {
+    if input.arrive_by {
+        return floodfill_pt_arrive_by(data, input, progress);
+    }
+    if input.profile.is_some() {
+        return floodfill_pt_profile(data, input, progress);
+    }
+
+    // Changes to the graph are layered over the cached graphs rather than applied to a copy
+    let graph = graph_for_request(data, input)?;
+    if graph.has_changes() {
+        println!("Floodfill request received, with changes to the graphs");
+    } else {
+        println!("Floodfill request received, without changes");
     }
 
     println!(
-        "input.new_build_additions.len(): {}",
-        input.new_build_additions.len()
+        "input.edits.new_build_additions.len(): {}",
+        input.edits.new_build_additions.len()
     );
 
-    let time_of_day_ix = get_time_of_day_index(input.trip_start_seconds);
-
-    let count_original_nodes = graph_walk.len() as u32;
+    let time_band = data.time_bands.band_name(input.trip_start_seconds);
 
     println!(
-        "Started running floodfill\ttime_of_day_ix: {}\tNodes count: {}",
-        time_of_day_ix,
+        "Started running floodfill\ttime band: {}\tNodes count: {}",
+        time_band,
         input.start_nodes_user_input.len()
     );
     
+    let results = get_scores_multicore(data, &graph, input, &input.target_destinations, progress)?;
+
+    if input.legacy_output {
+        return Ok(serde_json::to_string(&results).unwrap());
+    }
+    let results = results
+        .iter()
+        .map(|scores| FloodfillResult::from_scores(scores, &data.subpurpose_purpose_lookup))
+        .collect();
+    Ok(FloodfillResponse::to_json(FloodfillMode::Scores, results, false))
+}
+
+
+// Runs a floodfill per start node per departure time in the window, then summarises scores and
+// target destination travel times across the window for each start node. Only RAPTOR reuses work
+// between departure times; the Dijkstra floodfills are independent
+fn floodfill_pt_profile(
+    data: &AppState,
+    input: &UserInputJSON,
+    progress: Option<&JobProgress>,
+) -> Result<String, ConnectivityError> {
+    let graph = graph_for_request(data, input)?;
+    let profile = input.profile.as_ref().unwrap();
+    let departure_times: Vec<i32> = (input.trip_start_seconds..=profile.window_end_seconds)
+        .step_by(profile.step_seconds as usize)
+        .collect();
+    println!(
+        "Profile floodfill request received\tNodes count: {}\tDeparture times count: {}",
+        input.start_nodes_user_input.len(),
+        departure_times.len()
+    );
+
+    // The window might cross from one time of day to the next. Each band's curves are extended
+    // once, rather than once per departure time
+    let extended_curves = data
+        .time_bands
+        .extended_to(input.max_travel_time_seconds, input.decay_curve_extension);
+    let travel_time_relationships_per_departure: Vec<Cow<TravelTimeRelationships>> = departure_times
+        .iter()
+        .map(|departure_time| match &extended_curves {
+            Some(extended_curves) => data
+                .time_bands
+                .travel_time_relationships_among(*departure_time, extended_curves),
+            None => data.time_bands.travel_time_relationships(*departure_time),
+        })
+        .collect();
+
     let now = Instant::now();
-    
-    let (mut node_values_2d, travel_times) = parallel_node_values_read_and_floodfill(
-        &graph_walk,
-        &graph_pt,
-        &input,
+    // Every start node and departure time pair, so a few start nodes still use every core
+    let pairs: Vec<(usize, usize)> = (0..input.start_nodes_user_input.len())
+        .flat_map(|i| (0..departure_times.len()).map(move |j| (i, j)))
+        .collect();
+    // Each floodfill is scored as soon as it's done and only the scores are kept, as every start
+    // node's travel times for every departure time wouldn't fit in memory
+    let score = |travel_times: &FloodfillOutput, j: usize| {
+        get_all_scores_and_time_to_target_destinations(
+            travel_times,
+            &graph,
+            &travel_time_relationships_per_departure[j],
+            &data.subpurpose_purpose_lookup,
+            &input.target_destinations,
+        )
+    };
+    let scores_per_pair: Vec<FloodfillScores> = match get_timetable_if_needed(&graph, input) {
+        // rRAPTOR does a start node's whole window in one go
+        Some(timetable) => (0..input.start_nodes_user_input.len())
+            .into_par_iter()
+            .map(|i| {
+                let travel_times = get_travel_times_raptor_profile(
+                    &graph,
+                    &timetable,
+                    NodeID(input.start_nodes_user_input[i] as u32),
+                    &departure_times,
+                    Cost(input.init_travel_times_user_input[i] as u16),
+                    Cost(input.max_travel_time_seconds),
+                    input.max_boardings.unwrap_or(u8::MAX),
+                )?;
+                if let Some(progress) = progress {
+                    progress.start_node_done()?;
+                }
+                travel_times
+                    .iter()
+                    .enumerate()
+                    .map(|(j, travel_times)| score(travel_times, j))
+                    .collect::<Result<Vec<FloodfillScores>, _>>()
+            })
+            .collect::<Result<Vec<Vec<FloodfillScores>>, _>>()?
+            .into_iter()
+            .flatten()
+            .collect(),
+        None => pairs
+            .par_iter()
+            .map(|(i, j)| {
+                let travel_times =
+                    get_travel_times_for_start_node(&graph, None, input, *i, departure_times[*j])?;
+                // Pairs finish in any order, so this is only roughly when the start node is done
+                if let (Some(progress), true) = (progress, *j == departure_times.len() - 1) {
+                    progress.start_node_done()?;
+                }
+                score(&travel_times, *j)
+            })
+            .collect::<Result<_, _>>()?,
+    };
+    println!("Floodfills and scores for every departure time took {:?}", now.elapsed());
+
+    // Pairs are in start node order, so each chunk is one start node's departures
+    let results: Vec<ProfileResult> = scores_per_pair
+        .par_chunks(departure_times.len())
+        .zip(&input.start_nodes_user_input)
+        .map(|(scores_per_departure, start_node)| {
+            summarise_profile(
+                *start_node as u32,
+                scores_per_departure,
+                &input.target_destinations,
+                &profile.percentiles,
+            )
+        })
+        .collect();
+
+    Ok(FloodfillResponse::to_json(FloodfillMode::Profile, results, input.legacy_output))
+}
+
+
+// Start nodes are destinations and trip_start_seconds is when to arrive by. If there are target
+// destinations, only those origins are returned; otherwise every origin within the time limit
+fn floodfill_pt_arrive_by(
+    data: &AppState,
+    input: &UserInputJSON,
+    progress: Option<&JobProgress>,
+) -> Result<String, ConnectivityError> {
+    println!(
+        "Arrive-by floodfill request received\tNodes count: {}",
+        input.start_nodes_user_input.len()
     );
-        
-    println!("Node values read in and floodfill in parallel {:?}", now.elapsed());
-    
-    // Altering node_values to reflect changes in graph
-    /*
-    for _i in 0..input.graph_walk_additions.len() {
-        for _ in 0..32 {
-            node_values_1d.push(0);
-        }
-    }
-    let expected_len = graph_walk.len() * 32;
-    assert!(node_values_1d.len() == expected_len);
-    */
-    // the lines below do the equivalent to the above, with sparse node values: pushing empty vectors
-    for _i in 0..input.graph_walk_additions.len() {
-        let empty_vec: Vec<[i32; 2]> = Vec::new();
-        node_values_2d.push(empty_vec);
+    let graph = graph_for_request(data, input)?;
+    let reverse_graph = graph.reverse_graph();
+
+    let now = Instant::now();
+    let mut target_origins_binary_vec = vec![false; graph.node_count()];
+    for id in &input.target_destinations {
+        target_origins_binary_vec[*id as usize] = true;
     }
-    
-    
-    
-    // TODO Redundant conditional? (Adam in response - the below is edited to fix this; keeping comment in case error shows)
-    //if input.new_build_additions.len() >= 1 {
-    
-    
-    for new_build in &input.new_build_additions {
-        let value_to_add = new_build[0];
-        let index_of_nearest_node = new_build[1];
-        let subpurpose_ix = new_build[2];
-        
-        // add node value to current score if one can be found for this node for the new build's subpurpose
-        let mut loop_ix = 0;
-        let mut found_existing_subpurpose = false;
-        let values_vec_this_node = node_values_2d[index_of_nearest_node as usize].to_vec();
-        for subpurpose_score_pair in values_vec_this_node.iter() {
-            let subpurpose_ix_existing = subpurpose_score_pair[0];
-            if subpurpose_ix == subpurpose_ix_existing {
-                node_values_2d[index_of_nearest_node as usize][loop_ix][1] += value_to_add;
-                found_existing_subpurpose = true;
+
+    let indices = (0..input.start_nodes_user_input.len()).collect::<Vec<_>>();
+    let results: Vec<ArriveByResult> = indices
+        .par_iter()
+        .map(|i| {
+            let (destination, origin_ids, origin_travel_times) = get_travel_times_arrive_by(
+                &reverse_graph,
+                NodeID(input.start_nodes_user_input[*i] as u32),
+                input.trip_start_seconds,
+                Cost(input.init_travel_times_user_input[*i] as u16),
+                Cost(input.max_travel_time_seconds),
+            )?;
+            if let Some(progress) = progress {
+                progress.start_node_done()?;
             }
-            loop_ix += 1;
-        }
-        // append to node_values_2d if no value for that node's subpurpose
-        if !found_existing_subpurpose {
-            let subpurpose_value_to_add: [i32; 2] = [subpurpose_ix, value_to_add];
-            node_values_2d[index_of_nearest_node as usize].push(subpurpose_value_to_add);
-        }
-    }
-    // the 15 or so lines above replace the 6 lines below
-    /*
-    for new_build in &input.new_build_additions {
-        let value_to_add = new_build[0];
-        let index_of_nearest_node = new_build[1];
-        let column_to_change = new_build[2];
-        let ix = (index_of_nearest_node * 32) + column_to_change;
-        node_values_1d[ix as usize] += value_to_add;
-    }
-    */
-    //}
+            let mut result = ArriveByResult {
+                destination,
+                origins: vec![],
+                latest_departure_seconds: vec![],
+            };
+            for (origin, travel_time) in origin_ids.into_iter().zip(origin_travel_times) {
+                let Some(latest_departure) =
+                    (input.trip_start_seconds as u32).checked_sub(travel_time as u32)
+                else {
+                    continue;
+                };
+                if input.target_destinations.is_empty()
+                    || target_origins_binary_vec[origin as usize]
+                {
+                    result.origins.push(origin);
+                    result.latest_departure_seconds.push(latest_departure);
+                }
+            }
+            Ok(result)
+        })
+        .collect::<Result<_, ConnectivityError>>()?;
+    println!("Arrive-by floodfill took {:?}", now.elapsed());
+
+    Ok(FloodfillResponse::to_json(FloodfillMode::ArriveBy, results, input.legacy_output))
+}
+
+
+// Travel times from every start node, then scores and travel times to the target destinations
+fn get_scores_multicore(
+    data: &AppState,
+    graph: &GraphOverlay,
+    input: &UserInputJSON,
+    target_destinations: &[u32],
+    progress: Option<&JobProgress>,
+) -> Result<Vec<FloodfillScores>, ConnectivityError> {
+    let now = Instant::now();
+    let travel_times = get_travel_times_multicore(graph, input, progress)?;
+    println!("Floodfill took {:?}", now.elapsed());
     
+    let travel_time_relationships =
+        data.get_travel_time_relationships(input.trip_start_seconds, input);
+
     let now = Instant::now();
     let indices = (0..input.start_nodes_user_input.len()).collect::<Vec<_>>();
     
-    let results: Vec<(i32, u32, [i64; 32], Vec<u32>, Vec<u16>)> = indices
+    let results: Vec<FloodfillScores> = indices
         .par_iter()
         .map(|i| {
             get_all_scores_and_time_to_target_destinations(
                 &travel_times[*i],
-                &node_values_2d,
-                &data.travel_time_relationships_all[time_of_day_ix],
+                graph,
+                &travel_time_relationships,
                 &data.subpurpose_purpose_lookup,
-                count_original_nodes,
-                node_values_padding_row_count,
-                &input.target_destinations,
+                target_destinations,
             )
         })
-        .collect();
+        .collect::<Result<_, _>>()?;
     println!("Getting destinations and scores took {:?}", now.elapsed());
-    
-    serde_json::to_string(&results).unwrap()
+    Ok(results)
 }
 
-
 fn get_travel_times_multicore(
-    graph_walk: &Vec<SmallVec<[EdgeWalk; 4]>>,
-    graph_pt: &Vec<SmallVec<[EdgePT; 4]>>,
-    input: &web::Json<UserInputJSON>
-) -> Vec<(u32, Vec<u32>, Vec<u16>)> {
+    graph: &GraphOverlay,
+    input: &UserInputJSON,
+    progress: Option<&JobProgress>,
+) -> Result<Vec<FloodfillOutput>, ConnectivityError> {
         
     let indices = (0..input.start_nodes_user_input.len()).collect::<Vec<_>>();
+    let timetable = get_timetable_if_needed(graph, input);
     
-    return indices
+    indices
         .par_iter()
         .map(|i| {
-            get_travel_times(
-                &graph_walk,
-                &graph_pt,
-                NodeID(*&input.start_nodes_user_input[*i] as u32),
-                *&input.trip_start_seconds,
-                Cost(*&input.init_travel_times_user_input[*i] as u16),
-            )
+            let travel_times = get_travel_times_for_start_node(
+                graph,
+                timetable.as_deref(),
+                input,
+                *i,
+                input.trip_start_seconds,
+            )?;
+            if let Some(progress) = progress {
+                progress.start_node_done()?;
+            }
+            Ok(travel_times)
         })
-        .collect(); 
+        .collect()
 }
 
-
-
-fn parallel_node_values_read_and_floodfill(graph_walk: &Vec<SmallVec<[EdgeWalk; 4]>>,
-    graph_pt: &Vec<SmallVec<[EdgePT; 4]>>,
-    input: &web::Json<UserInputJSON>
-) -> (
-    Vec<Vec<[i32;2]>>, 
-    Vec<(u32, Vec<u32>, Vec<u16>)>
-) {
-        
-    let (node_values_2d, travel_times) = rayon::join(
-            || {
-                read_sparse_node_values_2d_serial(input.year)
-            },
-            || {
-                get_travel_times_multicore(
-                    &graph_walk,
-                    &graph_pt,
-                    &input,
-                )
-            },
-        );
-    (node_values_2d, travel_times)
+fn get_timetable_if_needed(graph: &GraphOverlay, input: &UserInputJSON) -> Option<Arc<Timetable>> {
+    match input.engine {
+        Engine::Dijkstra => None,
+        Engine::Raptor => Some(graph.timetable()),
+    }
 }
 
+// Dijkstra only counts boardings when the request limits or penalises them, as it's slower.
+// Takes the timetable if the request is for RAPTOR
+fn get_travel_times_for_start_node(
+    graph: &GraphOverlay,
+    timetable: Option<&Timetable>,
+    input: &UserInputJSON,
+    start_node_ix: usize,
+    trip_start_seconds: i32,
+) -> Result<FloodfillOutput, ConnectivityError> {
+    let start = NodeID(input.start_nodes_user_input[start_node_ix] as u32);
+    let init_travel_time = Cost(input.init_travel_times_user_input[start_node_ix] as u16);
+    let time_limit = Cost(input.max_travel_time_seconds);
+    if let Some(timetable) = timetable {
+        get_travel_times_raptor(
+            graph,
+            timetable,
+            start,
+            trip_start_seconds,
+            init_travel_time,
+            time_limit,
+            input.max_boardings.unwrap_or(u8::MAX),
+        )
+    } else if input.max_boardings.is_none() && input.boarding_penalty_seconds == 0 {
+        get_travel_times(graph, start, trip_start_seconds, init_travel_time, time_limit, None)
+    } else {
+        get_travel_times_with_boardings(
+            graph,
+            start,
+            trip_start_seconds,
+            init_travel_time,
+            time_limit,
+            // u8::MAX marks unreached nodes, so it can't be a boarding count
+            input.max_boardings.unwrap_or(u8::MAX - 1).min(u8::MAX - 1),
+            Cost(input.boarding_penalty_seconds),
+        )
+    }
+}
 
+/// Same input as /floodfill_pt/, plus `scenario_ids`. Runs the year's own network once, then
+/// each scenario, and returns how every start node's scores and target destination travel times
+/// change under each
+#[post("/compare/")]
+async fn compare_scenarios(
+    data: web::Data<AppState>,
+    input: web::Json<CompareInputJSON>,
+) -> Result<String, ConnectivityError> {
+    validate_compare_input(&input)?;
+    let CompareInputJSON {
+        request: input,
+        scenario_ids,
+    } = input.into_inner();
+    let year_data = data.year_data(input.year)?;
+    let base_node_count = year_data.graph_walk.len();
+    validate_base_start_nodes(&input, base_node_count)?;
 
-// No changes to the graph allowed
-fn floodfill_pt_no_changes(data: web::Data<AppState>, input: web::Json<UserInputJSON>) -> String {
-    
-    println!("Floodfill request received, without changes");
-    let time_of_day_ix = get_time_of_day_index(input.trip_start_seconds);
-    
-    let (graph_walk, graph_pt, node_values_padding_row_count) =
-        read_files_parallel_excluding_node_values(input.year);
-            
-    let count_original_nodes = graph_walk.len() as u32;
-    
+    // Check every scenario before running anything
+    let mut scenarios: Vec<(Option<String>, Arc<GraphOverlay>)> = vec![];
+    if input.scenario_id.is_some() || !input.edits.is_empty() {
+        scenarios.push((input.scenario_id.clone(), graph_for_request(&data, &input)?));
+    }
+    for scenario_id in &scenario_ids {
+        let graph = graph_for_scenario(&data, &input, scenario_id)?;
+        scenarios.push((Some(scenario_id.clone()), graph));
+    }
     println!(
-        "Started running floodfill and node values files read\ttime_of_day_ix: {}\tNodes count: {}",
-        time_of_day_ix,
-        input.start_nodes_user_input.len()
+        "Compare request received\tNodes count: {}\tScenarios count: {}",
+        input.start_nodes_user_input.len(),
+        scenarios.len()
     );
-    
-    let now = Instant::now();
-    
-    let (node_values_2d, travel_times) = parallel_node_values_read_and_floodfill(
-        &graph_walk,
-        &graph_pt,
+
+    let to_results = |scores: Vec<FloodfillScores>| -> Vec<FloodfillResult> {
+        scores
+            .iter()
+            .map(|scores| FloodfillResult::from_scores(scores, &data.subpurpose_purpose_lookup))
+            .collect()
+    };
+    // Target destinations added by a scenario can't be reached in the base
+    let base_target_destinations: Vec<u32> = input
+        .target_destinations
+        .iter()
+        .copied()
+        .filter(|node| (*node as usize) < base_node_count)
+        .collect();
+    let service_day = year_data.service_day(input.day_type)?;
+    let base_graph = GraphOverlay::new(year_data, service_day);
+    let base_results = to_results(get_scores_multicore(
+        &data,
+        &base_graph,
         &input,
+        &base_target_destinations,
+        None,
+    )?);
+
+    let mut comparisons = vec![];
+    for (scenario_id, graph) in scenarios {
+        let scenario_results = to_results(get_scores_multicore(
+            &data,
+            &graph,
+            &input,
+            &input.target_destinations,
+            None,
+        )?);
+        comparisons.push(ScenarioComparison {
+            scenario_id,
+            results: base_results
+                .iter()
+                .zip(&scenario_results)
+                .map(|(base, scenario)| ComparisonResult::new(base, scenario))
+                .collect(),
+        });
+    }
+    let response = CompareResponse {
+        version: COMPARE_RESPONSE_VERSION,
+        year: input.year,
+        comparisons,
+    };
+    Ok(serde_json::to_string(&response).unwrap())
+}
+
+/// Same input as /floodfill_pt/, but returns straight away with a job ID to poll. For requests
+/// too big to answer before the load balancer times out
+#[post("/jobs/floodfill_pt/")]
+async fn submit_floodfill_pt_job(
+    data: web::Data<AppState>,
+    input: web::Json<UserInputJSON>,
+) -> Result<HttpResponse, ConnectivityError> {
+    // Catch what we can now rather than making the client poll for it
+    validate_user_input(&input)?;
+    if input.stream {
+        return Err(ConnectivityError::invalid(
+            "stream",
+            "jobs can't stream; fetch the result once the job is done",
+        ));
+    }
+    let input = input.into_inner();
+    let worker_data = data.clone();
+    let id = data.job_store.submit(input.start_nodes_user_input.len(), move |progress| {
+        run_floodfill_pt(&worker_data, &input, Some(progress))
+    });
+    println!("Floodfill job {} submitted", id);
+    Ok(HttpResponse::Accepted().json(data.job_store.status(&id)?))
+}
+
+#[get("/jobs/{id}")]
+async fn get_job_status(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ConnectivityError> {
+    Ok(HttpResponse::Ok().json(data.job_store.status(&id)?))
+}
+
+#[get("/jobs/{id}/result")]
+async fn get_job_result(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+) -> Result<String, ConnectivityError> {
+    data.job_store.result(&id)
+}
+
+/// Cancels a running job, or forgets a finished one and its result
+#[delete("/jobs/{id}")]
+async fn delete_job(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ConnectivityError> {
+    Ok(HttpResponse::Ok().json(data.job_store.delete(&id)?))
+}
+
+/// Same input as /floodfill_pt/. Returns the fastest path found from each start node to each of
+/// its target destinations, leg by leg
+#[post("/route_pt/")]
+async fn route_pt(
+    data: web::Data<AppState>,
+    input: web::Json<UserInputJSON>,
+) -> Result<String, ConnectivityError> {
+    println!(
+        "Route request received\tNodes count: {}\tTarget destinations count: {}",
+        input.start_nodes_user_input.len(),
+        input.target_destinations.len()
     );
-        
-    println!("Node values read in and floodfill in parallel {:?}", now.elapsed());
-    
+    validate_route_input(&input)?;
+    let graph = graph_for_request(&data, &input)?;
+
     let now = Instant::now();
     let indices = (0..input.start_nodes_user_input.len()).collect::<Vec<_>>();
-    
-    let results: Vec<(i32, u32, [i64; 32], Vec<u32>, Vec<u16>)> = indices
+    let results: Vec<StartNodeRoutes> = indices
         .par_iter()
         .map(|i| {
-            get_all_scores_and_time_to_target_destinations(
-                &travel_times[*i],
-                &node_values_2d,
-                &data.travel_time_relationships_all[time_of_day_ix],
-                &data.subpurpose_purpose_lookup,
-                count_original_nodes,
-                node_values_padding_row_count,
-                &input.target_destinations,
-            )
+            let mut predecessors = Predecessors::default();
+            let start_node = input.start_nodes_user_input[*i] as u32;
+            get_travel_times(
+                &graph,
+                NodeID(start_node),
+                input.trip_start_seconds,
+                Cost(input.init_travel_times_user_input[*i] as u16),
+                Cost(input.max_travel_time_seconds),
+                Some(&mut predecessors),
+            )?;
+            Ok(StartNodeRoutes {
+                start_node,
+                routes: input
+                    .target_destinations
+                    .iter()
+                    .filter_map(|destination| {
+                        reconstruct_route(&predecessors, *destination, input.trip_start_seconds)
+                    })
+                    .collect(),
+            })
         })
-        .collect();
-    println!("Getting destinations and scores took {:?}", now.elapsed());
-    
-    serde_json::to_string(&results).unwrap()
+        .collect::<Result<_, ConnectivityError>>()?;
+    println!("Floodfill and route reconstruction took {:?}", now.elapsed());
+
+    Ok(serde_json::to_string(&results).unwrap())
 }
 
 #[actix_web::main]
@@ -316,35 +776,124 @@ async fn main() -> std::io::Result<()> {
         serialise_files::serialise_sparse_node_values_2d_all_years();
         create_graph_walk_len(year); 
     }
+
+    // make this true to rebuild this year's walk graph from data/osm_{year}.osm.pbf, walking at
+    // 4.8kph, with slopes from the ESRI ASCII elevation grid data/dem_{year}.asc if there is one.
+    // Run the GTFS import after
+    if false {
+        let dem_path = format!("data/dem_{}.asc", year);
+        let dem_path = Path::new(&dem_path).exists().then_some(dem_path.as_str());
+        osm_import::import_osm(year, 4.8, dem_path);
+    }
+
+    // make this true to rebuild this year's PT graph from data/gtfs_{year}.zip, after the files
+    // above have been serialised
+    if false {
+        gtfs_import::import_gtfs(year);
+    }
+
+    // make this true to compare finding each stop's departures within an hour on this year's
+    // network, then exit
+    if false {
+        benchmarks::benchmark_departures_between(year);
+        return Ok(());
+    }
     
-    let (
-        travel_time_relationships_7,
-        travel_time_relationships_10,
-        travel_time_relationships_16,
-        travel_time_relationships_19,
-        subpurpose_purpose_lookup,
-    ) = read_small_files_serial();
-
-    let travel_time_relationships_all = vec![
-        travel_time_relationships_7,
-        travel_time_relationships_10,
-        travel_time_relationships_16,
-        travel_time_relationships_19,
-    ];
-    let app_state = web::Data::new(AppState {
+    // Which decay curves to use when, from the manifest at TIME_BANDS_MANIFEST
+    let time_bands_manifest_path = std::env::var("TIME_BANDS_MANIFEST")
+        .unwrap_or_else(|_| "serialised_data/time_bands.json".to_string());
+    let time_bands_manifest = TimeBandsManifest::read(&time_bands_manifest_path)
+        .unwrap_or_else(|err| panic!("Couldn't read TIME_BANDS_MANIFEST: {}", err));
+    let (travel_time_relationships_all, subpurpose_purpose_lookup) =
+        read_small_files_serial(&time_bands_manifest.travel_time_relationships_files()).unwrap();
+
+    // Each travel time relationships file holds one decay curve per purpose, end to end
+    let purposes_count = *subpurpose_purpose_lookup.iter().max().unwrap() as usize + 1;
+    let travel_time_relationships_all: Vec<TravelTimeRelationships> = travel_time_relationships_all
+        .into_iter()
+        .map(|values| TravelTimeRelationships::new(values, purposes_count))
+        .collect();
+    let time_bands = TimeBands::new(
+        time_bands_manifest,
         travel_time_relationships_all,
+        &time_bands_manifest_path,
+    )
+    .unwrap_or_else(|err| panic!("TIME_BANDS_MANIFEST doesn't match its files: {}", err));
+    // Years to load before the API starts listening, eg "2019,2022". Other years are loaded the
+    // first time they're requested
+    let preload_years: Vec<i32> = std::env::var("PRELOAD_YEARS")
+        .unwrap_or_else(|_| year.to_string())
+        .split(',')
+        .filter(|year| !year.trim().is_empty())
+        .map(|year| {
+            year.trim()
+                .parse()
+                .unwrap_or_else(|_| panic!("PRELOAD_YEARS has {:?}, which isn't a year", year))
+        })
+        .collect();
+
+    // Least recently used years are evicted once the cached graphs take up more than this. Each
+    // year needs about 3gb
+    let graph_cache_memory_budget_gb: usize = std::env::var("GRAPH_CACHE_MEMORY_BUDGET_GB")
+        .map(|gb| gb.parse().expect("GRAPH_CACHE_MEMORY_BUDGET_GB should be a whole number"))
+        .unwrap_or(16);
+
+    let graph_cache = GraphCache::new(graph_cache_memory_budget_gb * 1024 * 1024 * 1024);
+    graph_cache
+        .preload(&preload_years)
+        .unwrap_or_else(|err| panic!("Couldn't load PRELOAD_YEARS: {}", err));
+
+    // Job results bigger than JOB_SPILL_THRESHOLD_MB are written to JOB_RESULTS_DIR rather than
+    // held in memory. Finished jobs are forgotten after JOB_RETENTION_HOURS
+    let job_results_dir =
+        std::env::var("JOB_RESULTS_DIR").unwrap_or_else(|_| "job_results".to_string());
+    let job_spill_threshold_mb: usize = std::env::var("JOB_SPILL_THRESHOLD_MB")
+        .map(|mb| mb.parse().expect("JOB_SPILL_THRESHOLD_MB should be a whole number"))
+        .unwrap_or(64);
+    let job_retention_hours: u64 = std::env::var("JOB_RETENTION_HOURS")
+        .map(|hours| hours.parse().expect("JOB_RETENTION_HOURS should be a whole number"))
+        .unwrap_or(24);
+    let job_store = Arc::new(JobStore::new(
+        PathBuf::from(job_results_dir),
+        job_spill_threshold_mb * 1024 * 1024,
+        Duration::from_secs(job_retention_hours * 3600),
+    ));
+
+    // Scenarios from POST /scenarios/ are kept here as JSON, one file each
+    let scenarios_dir =
+        std::env::var("SCENARIOS_DIR").unwrap_or_else(|_| "scenarios".to_string());
+    let scenario_store = ScenarioStore::new(PathBuf::from(scenarios_dir));
+
+    let app_state = web::Data::new(AppState {
+        time_bands,
         subpurpose_purpose_lookup,
+        graph_cache,
+        job_store,
+        scenario_store,
     });
     HttpServer::new(move || {
         App::new()
-            // This clone is of an Arc from actix. AppState is immutable, and only one copy exists
-            // (except for when we clone some pieces of it to make mutations scoped to a single
-            // request.)
+            // This clone is of an Arc from actix. AppState is immutable apart from the graph cache
+            // filling up, and only one copy exists (except for when we clone some pieces of it to
+            // make mutations scoped to a single request.)
             .app_data(app_state.clone())
-            .data(web::JsonConfig::default().limit(1024 * 1024 * 50)) // allow POST'd JSON payloads up to 50mb
+            // allow POST'd JSON payloads up to 50mb. Bodies which don't parse get the same JSON
+            // error as ones which fail validation
+            .app_data(
+                web::JsonConfig::default()
+                    .limit(1024 * 1024 * 50)
+                    .error_handler(|err, _| ConnectivityError::invalid("body", err.to_string()).into()),
+            )
             .service(index)
             .service(get_node_id_count)
             .service(floodfill_pt)
+            .service(route_pt)
+            .service(submit_floodfill_pt_job)
+            .service(get_job_status)
+            .service(get_job_result)
+            .service(delete_job)
+            .service(create_scenario)
+            .service(compare_scenarios)
     })
     .bind(("0.0.0.0", 7328))?
     .run()
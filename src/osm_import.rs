@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use smallvec::{smallvec, SmallVec};
+
+use crate::gtfs_import::write_bincode;
+use crate::osm_pbf::{read_nodes, read_ways};
+use crate::read_files::create_graph_walk_len;
+use crate::shared::{Cost, EdgePT, EdgeWalk, GraphWalk, NodeID};
+
+/// Highways anyone can walk along unless they're tagged otherwise
+const WALKABLE_HIGHWAYS: [&str; 24] = [
+    "footway",
+    "path",
+    "pedestrian",
+    "steps",
+    "corridor",
+    "living_street",
+    "residential",
+    "service",
+    "unclassified",
+    "road",
+    "track",
+    "cycleway",
+    "bridleway",
+    "trunk",
+    "trunk_link",
+    "primary",
+    "primary_link",
+    "secondary",
+    "secondary_link",
+    "tertiary",
+    "tertiary_link",
+    "platform",
+    "crossing",
+    "busway",
+];
+
+/// Builds the year's walk graph from `data/osm_{year}.osm.pbf`. Nodes are the ends of walkable
+/// ways and the places they meet, numbered in OSM node ID order, and every stretch of way between
+/// them is an edge each way taking its length over `walking_speed_kph`. With `dem_path`, an ESRI
+/// ASCII grid of elevations in metres on a longitude/latitude grid, times also follow Tobler's
+/// hiking function, so uphill takes longer than downhill.
+///
+/// Writes the walk graph with every node's PT flag unset, a PT graph with no departures to match
+/// (gtfs_import fills it in), `data/node_coordinates_{year}.json` for gtfs_import to snap stops
+/// to, and a node values padding row count of 0, as there are no nodes just for stops. Node
+/// values for the year need rebuilding for the new node IDs
+pub fn import_osm(year: i32, walking_speed_kph: f64, dem_path: Option<&str>) {
+    let now = Instant::now();
+    let path = format!("data/osm_{}.osm.pbf", year);
+    let walking_speed = walking_speed_kph / 3.6;
+    let dem = dem_path.map(|dem_path| {
+        let dem = ElevationGrid::read(dem_path);
+        println!(
+            "Read {}x{} elevation grid from {}",
+            dem.ncols, dem.nrows, dem_path
+        );
+        dem
+    });
+
+    // Ways first, to find which nodes are needed and which are junctions
+    let mut ways: Vec<Vec<i64>> = vec![];
+    let mut uses_by_node: HashMap<i64, u8> = HashMap::new();
+    read_ways(&path, |tags, refs| {
+        if refs.len() < 2 || !is_walkable(tags) {
+            return;
+        }
+        for (ix, node) in refs.iter().enumerate() {
+            let uses = uses_by_node.entry(*node).or_default();
+            // Ends of ways are always graph nodes
+            let is_end = ix == 0 || ix == refs.len() - 1;
+            *uses = uses.saturating_add(if is_end { 2 } else { 1 });
+        }
+        ways.push(refs.to_vec());
+    })
+    .unwrap();
+    println!("Read {} walkable ways from {}", ways.len(), path);
+
+    let mut coordinates_by_node: HashMap<i64, [f64; 2]> = HashMap::new();
+    read_nodes(&path, |id, lon, lat| {
+        if uses_by_node.contains_key(&id) {
+            coordinates_by_node.insert(id, [lon, lat]);
+        }
+    })
+    .unwrap();
+
+    let mut graph_nodes: Vec<i64> = uses_by_node
+        .iter()
+        .filter(|(node, uses)| **uses >= 2 && coordinates_by_node.contains_key(node))
+        .map(|(node, _)| *node)
+        .collect();
+    graph_nodes.sort_unstable();
+    let node_ix_by_osm_id: HashMap<i64, u32> = graph_nodes
+        .iter()
+        .enumerate()
+        .map(|(ix, node)| (*node, ix as u32))
+        .collect();
+
+    let pt_flag = EdgeWalk {
+        to: NodeID(0),
+        cost: Cost(0),
+    };
+    let mut graph_walk: GraphWalk = vec![smallvec![pt_flag]; graph_nodes.len()];
+    let mut edges_count = 0;
+    for refs in &ways {
+        // The graph node this stretch started at, and the seconds to walk it each way so far
+        let mut stretch: Option<(u32, f64, f64)> = None;
+        for pair in refs.windows(2) {
+            let (Some(from), Some(to)) = (
+                coordinates_by_node.get(&pair[0]),
+                coordinates_by_node.get(&pair[1]),
+            ) else {
+                // Runs off the edge of the extract
+                stretch = None;
+                continue;
+            };
+            if stretch.is_none() {
+                stretch = node_ix_by_osm_id
+                    .get(&pair[0])
+                    .map(|start| (*start, 0.0, 0.0));
+            }
+            let Some((start, forwards, backwards)) = stretch.as_mut() else {
+                continue;
+            };
+            let (segment_forwards, segment_backwards) =
+                segment_seconds(*from, *to, walking_speed, dem.as_ref());
+            *forwards += segment_forwards;
+            *backwards += segment_backwards;
+
+            if let Some(end) = node_ix_by_osm_id.get(&pair[1]) {
+                if *end != *start {
+                    add_edge(&mut graph_walk, *start, *end, *forwards);
+                    add_edge(&mut graph_walk, *end, *start, *backwards);
+                    edges_count += 2;
+                }
+                stretch = Some((*end, 0.0, 0.0));
+            }
+        }
+    }
+    println!(
+        "Built walk graph with {} nodes and {} edges",
+        graph_walk.len(),
+        edges_count
+    );
+
+    let coordinates: Vec<[f64; 2]> = graph_nodes
+        .iter()
+        .map(|node| coordinates_by_node[node])
+        .collect();
+    let coordinates_path = format!("data/node_coordinates_{}.json", year);
+    fs_err::write(
+        &coordinates_path,
+        serde_json::to_string(&coordinates).unwrap(),
+    )
+    .unwrap();
+    println!("Wrote {}", coordinates_path);
+
+    let graph_pt: Vec<SmallVec<[EdgePT; 4]>> = vec![SmallVec::new(); graph_walk.len()];
+    write_bincode(&format!("p1_main_nodes_vector_6am_{year}"), &graph_walk);
+    write_bincode(&format!("p2_departures_vector_6am_{year}"), &graph_pt);
+    write_bincode(&format!("node_values_padding_row_count_6am_{year}"), &0u32);
+    create_graph_walk_len(year);
+    println!("OSM import for year {} took {:?}", year, now.elapsed());
+}
+
+fn is_walkable(tags: &[(&str, &str)]) -> bool {
+    let tag = |key: &str| {
+        tags.iter()
+            .find(|(tag_key, _)| *tag_key == key)
+            .map(|(_, value)| *value)
+    };
+    let Some(highway) = tag("highway") else {
+        return false;
+    };
+    if let Some(foot) = tag("foot") {
+        if foot == "no" || foot == "private" {
+            return false;
+        }
+        if foot == "yes" || foot == "designated" || foot == "permissive" {
+            return true;
+        }
+    }
+    if matches!(tag("access"), Some("no" | "private")) {
+        return false;
+    }
+    WALKABLE_HIGHWAYS.contains(&highway)
+}
+
+/// Keeps the quicker of two edges between the same nodes
+fn add_edge(graph_walk: &mut GraphWalk, from: u32, to: u32, seconds: f64) {
+    let cost = Cost(seconds.round().clamp(1.0, u16::MAX as f64) as u16);
+    let edges = &mut graph_walk[from as usize];
+    match edges[1..].iter_mut().find(|edge| edge.to.0 == to) {
+        Some(edge) => edge.cost = edge.cost.min(cost),
+        None => edges.push(EdgeWalk {
+            to: NodeID(to),
+            cost,
+        }),
+    }
+}
+
+/// Seconds to walk between two points [longitude, latitude], forwards then backwards
+fn segment_seconds(
+    from: [f64; 2],
+    to: [f64; 2],
+    walking_speed: f64,
+    dem: Option<&ElevationGrid>,
+) -> (f64, f64) {
+    let metres = distance_metres(from, to);
+    let flat_seconds = metres / walking_speed;
+    let elevations = dem.and_then(|dem| Some((dem.elevation(from)?, dem.elevation(to)?)));
+    match elevations {
+        Some((from_elevation, to_elevation)) if metres > 0.0 => {
+            let slope = (to_elevation - from_elevation) / metres;
+            (
+                flat_seconds * tobler_multiplier(slope),
+                flat_seconds * tobler_multiplier(-slope),
+            )
+        }
+        _ => (flat_seconds, flat_seconds),
+    }
+}
+
+/// How much longer than on the flat it takes to walk up a slope (rise over run), from Tobler's
+/// hiking function. Walking is fastest slightly downhill
+fn tobler_multiplier(slope: f64) -> f64 {
+    (3.5 * ((slope + 0.05).abs() - 0.05)).exp()
+}
+
+/// Haversine distance between two [longitude, latitude] points
+fn distance_metres(a: [f64; 2], b: [f64; 2]) -> f64 {
+    const EARTH_RADIUS_METRES: f64 = 6_371_000.0;
+    let (lat_a, lat_b) = (a[1].to_radians(), b[1].to_radians());
+    let half_chord = ((lat_b - lat_a) / 2.0).sin().powi(2)
+        + lat_a.cos() * lat_b.cos() * ((b[0] - a[0]).to_radians() / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METRES * half_chord.sqrt().asin()
+}
+
+/// Elevations from an ESRI ASCII grid, with cells in degrees of longitude and latitude
+struct ElevationGrid {
+    ncols: usize,
+    nrows: usize,
+    /// Centre of the bottom left cell
+    x_first: f64,
+    y_first: f64,
+    cellsize: f64,
+    nodata: Option<f64>,
+    /// Rows from north to south, as in the file
+    values: Vec<f64>,
+}
+
+impl ElevationGrid {
+    fn read(path: &str) -> ElevationGrid {
+        let contents = fs_err::read_to_string(path).unwrap();
+        let mut tokens = contents.split_whitespace().peekable();
+        let mut header: HashMap<String, f64> = HashMap::new();
+        // The header is keyword value pairs, before the first number
+        while let Some(token) = tokens.peek() {
+            if token.parse::<f64>().is_ok() {
+                break;
+            }
+            let key = tokens.next().unwrap().to_lowercase();
+            let value = tokens.next().and_then(|value| value.parse().ok());
+            header.insert(
+                key.clone(),
+                value.unwrap_or_else(|| panic!("No value for {}", key)),
+            );
+        }
+        let field = |key: &str| {
+            *header
+                .get(key)
+                .unwrap_or_else(|| panic!("No {} in {}", key, path))
+        };
+        let (ncols, nrows, cellsize) = (
+            field("ncols") as usize,
+            field("nrows") as usize,
+            field("cellsize"),
+        );
+        // Corners are the outside edge of the bottom left cell, centres the middle of it
+        let (x_first, y_first) = match (header.get("xllcenter"), header.get("yllcenter")) {
+            (Some(x), Some(y)) => (*x, *y),
+            _ => (
+                field("xllcorner") + cellsize / 2.0,
+                field("yllcorner") + cellsize / 2.0,
+            ),
+        };
+        let values: Vec<f64> = tokens.map(|token| token.parse().unwrap()).collect();
+        assert!(
+            values.len() == ncols * nrows,
+            "{} should have {} values but has {}",
+            path,
+            ncols * nrows,
+            values.len()
+        );
+        ElevationGrid {
+            ncols,
+            nrows,
+            x_first,
+            y_first,
+            cellsize,
+            nodata: header.get("nodata_value").copied(),
+            values,
+        }
+    }
+
+    /// Bilinear interpolation between the centres of the four nearest cells. None off the grid or
+    /// next to a cell without data
+    fn elevation(&self, point: [f64; 2]) -> Option<f64> {
+        let x = (point[0] - self.x_first) / self.cellsize;
+        let y = (point[1] - self.y_first) / self.cellsize;
+        if x < 0.0 || y < 0.0 || x > (self.ncols - 1) as f64 || y > (self.nrows - 1) as f64 {
+            return None;
+        }
+        let (col, row) = (
+            (x as usize).min(self.ncols.saturating_sub(2)),
+            (y as usize).min(self.nrows.saturating_sub(2)),
+        );
+        let (dx, dy) = (x - col as f64, y - row as f64);
+        let at = |col: usize, row_from_south: usize| {
+            let col = col.min(self.ncols - 1);
+            let row_from_south = row_from_south.min(self.nrows - 1);
+            let value = self.values[(self.nrows - 1 - row_from_south) * self.ncols + col];
+            match self.nodata {
+                Some(nodata) if value == nodata => None,
+                _ => Some(value),
+            }
+        };
+        let south = at(col, row)? * (1.0 - dx) + at(col + 1, row)? * dx;
+        let north = at(col, row + 1)? * (1.0 - dx) + at(col + 1, row + 1)? * dx;
+        Some(south * (1.0 - dy) + north * dy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 4 by 3 cells 0.001 degrees across, rising 10m a column eastwards, with no data in the
+    /// north east corner
+    fn dem(name: &str) -> ElevationGrid {
+        let path = std::env::temp_dir().join(format!("{}_{}.asc", name, std::process::id()));
+        std::fs::write(
+            &path,
+            "ncols 4\nnrows 3\nxllcorner -0.1\nyllcorner 51.5\ncellsize 0.001\nNODATA_value -9999\n\
+             0 10 20 -9999\n0 10 20 30\n0 10 20 30\n",
+        )
+        .unwrap();
+        let dem = ElevationGrid::read(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        dem
+    }
+
+    #[test]
+    fn elevations_are_interpolated_between_cell_centres() {
+        let dem = dem("interpolated");
+        // Halfway between the first two cell centres in the bottom row
+        let elevation = dem.elevation([-0.099, 51.5005]).unwrap();
+        assert!((elevation - 5.0).abs() < 1e-6, "{}", elevation);
+        assert_eq!(dem.elevation([-0.101, 51.5005]), None);
+        // Next to the cell without data
+        assert_eq!(dem.elevation([-0.0968, 51.5022]), None);
+    }
+
+    #[test]
+    fn uphill_takes_longer_than_downhill() {
+        let dem = dem("slopes");
+        let (west, east) = ([-0.0995, 51.5005], [-0.0975, 51.5005]);
+        let (flat, _) = segment_seconds(west, east, 1.0, None);
+        let (uphill, downhill) = segment_seconds(west, east, 1.0, Some(&dem));
+        let slope = 20.0 / distance_metres(west, east);
+        assert!((uphill / flat - tobler_multiplier(slope)).abs() < 1e-9);
+        assert!((downhill / flat - tobler_multiplier(-slope)).abs() < 1e-9);
+        // Steep enough that going down is slower than the flat too, but not as slow as going up
+        assert!(uphill > downhill && downhill > flat);
+    }
+}
@@ -0,0 +1,559 @@
+use std::io::{self, BufReader, Read};
+
+use flate2::read::ZlibDecoder;
+use fs_err::File;
+
+// Just enough of the OpenStreetMap PBF format to read nodes and ways: blobs must be raw or zlib
+// compressed, and history, changesets and relations are skipped.
+// See https://wiki.openstreetmap.org/wiki/PBF_Format
+
+/// Calls `on_way` with the tags and node IDs of every way in the file
+pub fn read_ways(path: &str, mut on_way: impl FnMut(&[(&str, &str)], &[i64])) -> io::Result<()> {
+    let mut refs: Vec<i64> = vec![];
+    read_primitive_blocks(path, |block| {
+        let strings = string_table(block)?;
+        let mut tags: Vec<(&str, &str)> = vec![];
+        for group in fields_numbered(block, 2) {
+            for way in fields_numbered(group?, 3) {
+                let way = way?;
+                tags.clear();
+                refs.clear();
+                let (mut keys, mut vals) = (vec![], vec![]);
+                let mut fields = ProtoReader::new(way);
+                while let Some((field, value)) = fields.next_field()? {
+                    match field {
+                        2 => keys.extend(value.packed_varints()?),
+                        3 => vals.extend(value.packed_varints()?),
+                        8 => {
+                            let mut id = 0;
+                            for delta in value.packed_varints()? {
+                                id += zigzag(delta);
+                                refs.push(id);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                for (key, val) in keys.iter().zip(&vals) {
+                    tags.push((string_at(&strings, *key)?, string_at(&strings, *val)?));
+                }
+                on_way(&tags, &refs);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Calls `on_node` with the ID, longitude and latitude of every node in the file
+pub fn read_nodes(path: &str, mut on_node: impl FnMut(i64, f64, f64)) -> io::Result<()> {
+    read_primitive_blocks(path, |block| {
+        let mut granularity = 100;
+        let (mut lat_offset, mut lon_offset) = (0, 0);
+        let mut fields = ProtoReader::new(block);
+        while let Some((field, value)) = fields.next_field()? {
+            match field {
+                17 => granularity = value.varint()? as i64,
+                19 => lat_offset = value.varint()? as i64,
+                20 => lon_offset = value.varint()? as i64,
+                _ => {}
+            }
+        }
+        let degrees = |offset: i64, value: i64| 1e-9 * (offset + granularity * value) as f64;
+
+        for group in fields_numbered(block, 2) {
+            let mut fields = ProtoReader::new(group?);
+            while let Some((field, value)) = fields.next_field()? {
+                match field {
+                    // Plain nodes
+                    1 => {
+                        let (mut id, mut lat, mut lon) = (0, 0, 0);
+                        let mut node_fields = ProtoReader::new(value.bytes()?);
+                        while let Some((field, value)) = node_fields.next_field()? {
+                            match field {
+                                1 => id = zigzag(value.varint()?),
+                                8 => lat = zigzag(value.varint()?),
+                                9 => lon = zigzag(value.varint()?),
+                                _ => {}
+                            }
+                        }
+                        on_node(id, degrees(lon_offset, lon), degrees(lat_offset, lat));
+                    }
+                    // Dense nodes, delta coded
+                    2 => {
+                        let (mut ids, mut lats, mut lons) = (vec![], vec![], vec![]);
+                        let mut dense_fields = ProtoReader::new(value.bytes()?);
+                        while let Some((field, value)) = dense_fields.next_field()? {
+                            match field {
+                                1 => ids = value.packed_varints()?,
+                                8 => lats = value.packed_varints()?,
+                                9 => lons = value.packed_varints()?,
+                                _ => {}
+                            }
+                        }
+                        let (mut id, mut lat, mut lon) = (0, 0, 0);
+                        for ix in 0..ids.len().min(lats.len()).min(lons.len()) {
+                            id += zigzag(ids[ix]);
+                            lat += zigzag(lats[ix]);
+                            lon += zigzag(lons[ix]);
+                            on_node(id, degrees(lon_offset, lon), degrees(lat_offset, lat));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Calls `on_block` with every decompressed OSMData blob, after checking the header doesn't need
+/// features this reader doesn't have
+fn read_primitive_blocks(
+    path: &str,
+    mut on_block: impl FnMut(&[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut file = BufReader::new(File::open(path)?);
+    loop {
+        let mut header_size = [0u8; 4];
+        match file.read_exact(&mut header_size) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        }
+        let header_size = u32::from_be_bytes(header_size) as usize;
+        if header_size > 64 * 1024 {
+            return Err(invalid_data("blob header over 64KB"));
+        }
+        let header = read_bytes(&mut file, header_size)?;
+        let (mut blob_type, mut blob_size) = (String::new(), 0);
+        let mut fields = ProtoReader::new(&header);
+        while let Some((field, value)) = fields.next_field()? {
+            match field {
+                1 => blob_type = String::from_utf8_lossy(value.bytes()?).into_owned(),
+                3 => blob_size = value.varint()? as usize,
+                _ => {}
+            }
+        }
+        if blob_size > 32 * 1024 * 1024 {
+            return Err(invalid_data("blob over 32MB"));
+        }
+        let blob = read_bytes(&mut file, blob_size)?;
+        let data = decompress_blob(&blob)?;
+        match blob_type.as_str() {
+            "OSMHeader" => check_required_features(&data)?,
+            "OSMData" => on_block(&data)?,
+            // Unknown blob types are meant to be skipped
+            _ => {}
+        }
+    }
+}
+
+fn decompress_blob(blob: &[u8]) -> io::Result<Vec<u8>> {
+    let mut raw_size = 0;
+    let mut fields = ProtoReader::new(blob);
+    while let Some((field, value)) = fields.next_field()? {
+        match field {
+            1 => return Ok(value.bytes()?.to_vec()),
+            2 => raw_size = value.varint()? as usize,
+            3 => {
+                let mut data = Vec::with_capacity(raw_size);
+                ZlibDecoder::new(value.bytes()?).read_to_end(&mut data)?;
+                return Ok(data);
+            }
+            4..=7 => {
+                return Err(invalid_data(
+                    "only raw and zlib compressed blobs are supported",
+                ))
+            }
+            _ => {}
+        }
+    }
+    Err(invalid_data("blob has no data"))
+}
+
+fn check_required_features(header_block: &[u8]) -> io::Result<()> {
+    let mut fields = ProtoReader::new(header_block);
+    while let Some((field, value)) = fields.next_field()? {
+        if field == 4 {
+            let feature = String::from_utf8_lossy(value.bytes()?);
+            if feature != "OsmSchema-V0.6" && feature != "DenseNodes" {
+                return Err(invalid_data(format!("unsupported feature {}", feature)));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn string_table(block: &[u8]) -> io::Result<Vec<&str>> {
+    let mut strings = vec![];
+    for table in fields_numbered(block, 1) {
+        for string in fields_numbered(table?, 1) {
+            strings.push(std::str::from_utf8(string?).map_err(invalid_data)?);
+        }
+    }
+    Ok(strings)
+}
+
+fn string_at<'a>(strings: &[&'a str], ix: u64) -> io::Result<&'a str> {
+    strings
+        .get(ix as usize)
+        .copied()
+        .ok_or_else(|| invalid_data("string index out of range"))
+}
+
+/// The contents of every length delimited field with this number
+fn fields_numbered(message: &[u8], number: u32) -> impl Iterator<Item = io::Result<&[u8]>> {
+    let mut fields = ProtoReader::new(message);
+    std::iter::from_fn(move || loop {
+        match fields.next_field() {
+            Ok(Some((field, value))) if field == number => return Some(value.bytes()),
+            Ok(Some(_)) => continue,
+            Ok(None) => return None,
+            Err(err) => return Some(Err(err)),
+        }
+    })
+}
+
+fn read_bytes(file: &mut impl Read, size: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; size];
+    file.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn zigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+fn invalid_data(message: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+enum ProtoValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+impl<'a> ProtoValue<'a> {
+    fn varint(&self) -> io::Result<u64> {
+        match self {
+            ProtoValue::Varint(value) => Ok(*value),
+            _ => Err(invalid_data("expected a varint")),
+        }
+    }
+
+    fn bytes(&self) -> io::Result<&'a [u8]> {
+        match self {
+            ProtoValue::Bytes(bytes) => Ok(bytes),
+            _ => Err(invalid_data("expected a length delimited field")),
+        }
+    }
+
+    /// Repeated numbers, whether packed or not
+    fn packed_varints(&self) -> io::Result<Vec<u64>> {
+        match self {
+            ProtoValue::Varint(value) => Ok(vec![*value]),
+            ProtoValue::Bytes(bytes) => {
+                let mut reader = ProtoReader::new(bytes);
+                let mut values = vec![];
+                while reader.position < bytes.len() {
+                    values.push(reader.varint()?);
+                }
+                Ok(values)
+            }
+            ProtoValue::Fixed => Err(invalid_data("expected varints")),
+        }
+    }
+}
+
+/// Reads protobuf fields one at a time, without a schema
+struct ProtoReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ProtoReader<'a> {
+    fn new(data: &'a [u8]) -> ProtoReader<'a> {
+        ProtoReader { data, position: 0 }
+    }
+
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self
+                .data
+                .get(self.position)
+                .ok_or_else(|| invalid_data("truncated varint"))?;
+            self.position += 1;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid_data("varint too long"))
+    }
+
+    fn next_field(&mut self) -> io::Result<Option<(u32, ProtoValue<'a>)>> {
+        if self.position >= self.data.len() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 7 {
+            0 => ProtoValue::Varint(self.varint()?),
+            1 => self.skip(8)?,
+            2 => {
+                let length = self.varint()? as usize;
+                let end = self
+                    .position
+                    .checked_add(length)
+                    .filter(|end| *end <= self.data.len());
+                let end = end.ok_or_else(|| invalid_data("truncated field"))?;
+                let bytes = &self.data[self.position..end];
+                self.position = end;
+                ProtoValue::Bytes(bytes)
+            }
+            5 => self.skip(4)?,
+            wire_type => return Err(invalid_data(format!("unsupported wire type {}", wire_type))),
+        };
+        Ok(Some(((key >> 3) as u32, value)))
+    }
+
+    fn skip(&mut self, length: usize) -> io::Result<ProtoValue<'a>> {
+        if self.position + length > self.data.len() {
+            return Err(invalid_data("truncated field"));
+        }
+        self.position += length;
+        Ok(ProtoValue::Fixed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+
+    use super::*;
+
+    fn varint(mut value: u64) -> Vec<u8> {
+        let mut bytes = vec![];
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(byte);
+                return bytes;
+            }
+            bytes.push(byte | 0x80);
+        }
+    }
+
+    fn varint_field(number: u32, value: u64) -> Vec<u8> {
+        let mut bytes = varint((number as u64) << 3);
+        bytes.extend(varint(value));
+        bytes
+    }
+
+    fn bytes_field(number: u32, value: &[u8]) -> Vec<u8> {
+        let mut bytes = varint((number as u64) << 3 | 2);
+        bytes.extend(varint(value.len() as u64));
+        bytes.extend(value);
+        bytes
+    }
+
+    fn packed_field(number: u32, values: &[u64]) -> Vec<u8> {
+        let packed: Vec<u8> = values.iter().flat_map(|value| varint(*value)).collect();
+        bytes_field(number, &packed)
+    }
+
+    fn unzigzag(value: i64) -> u64 {
+        ((value << 1) ^ (value >> 63)) as u64
+    }
+
+    fn deltas(values: &[i64]) -> Vec<u64> {
+        let mut previous = 0;
+        values
+            .iter()
+            .map(|value| {
+                let delta = unzigzag(value - previous);
+                previous = *value;
+                delta
+            })
+            .collect()
+    }
+
+    fn blob(blob_type: &str, data: &[u8], compress: bool) -> Vec<u8> {
+        let blob = if compress {
+            let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+            encoder.write_all(data).unwrap();
+            let mut blob = varint_field(2, data.len() as u64);
+            blob.extend(bytes_field(3, &encoder.finish().unwrap()));
+            blob
+        } else {
+            bytes_field(1, data)
+        };
+        let mut header = bytes_field(1, blob_type.as_bytes());
+        header.extend(varint_field(3, blob.len() as u64));
+        let mut bytes = (header.len() as u32).to_be_bytes().to_vec();
+        bytes.extend(header);
+        bytes.extend(blob);
+        bytes
+    }
+
+    fn header_blob(features: &[&str]) -> Vec<u8> {
+        let header: Vec<u8> = features
+            .iter()
+            .flat_map(|feature| bytes_field(4, feature.as_bytes()))
+            .collect();
+        blob("OSMHeader", &header, false)
+    }
+
+    fn string_table(strings: &[&str]) -> Vec<u8> {
+        let table: Vec<u8> = strings
+            .iter()
+            .flat_map(|string| bytes_field(1, string.as_bytes()))
+            .collect();
+        bytes_field(1, &table)
+    }
+
+    /// Dense nodes as (ID, lat, lon), in units of the block's granularity
+    fn dense_nodes_group(nodes: &[(i64, i64, i64)]) -> Vec<u8> {
+        let column = |pick: fn(&(i64, i64, i64)) -> i64| -> Vec<i64> {
+            nodes.iter().map(pick).collect()
+        };
+        let mut dense = packed_field(1, &deltas(&column(|node| node.0)));
+        dense.extend(packed_field(8, &deltas(&column(|node| node.1))));
+        dense.extend(packed_field(9, &deltas(&column(|node| node.2))));
+        bytes_field(2, &bytes_field(2, &dense))
+    }
+
+    fn write_fixture(name: &str, blobs: &[Vec<u8>]) -> String {
+        let path = std::env::temp_dir().join(format!("{}_{}.osm.pbf", name, std::process::id()));
+        std::fs::write(&path, blobs.concat()).unwrap();
+        path.display().to_string()
+    }
+
+    fn nodes_in(path: &str) -> Vec<(i64, f64, f64)> {
+        let mut nodes = vec![];
+        read_nodes(path, |id, lon, lat| nodes.push((id, lon, lat))).unwrap();
+        nodes
+    }
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} isn't {}", actual, expected);
+    }
+
+    #[test]
+    fn dense_nodes_are_delta_decoded() {
+        // IDs and coordinates going down as well as up, in the default 100 nanodegree units
+        let dense = [
+            (5, 515_000_000, -1_000_000),
+            (3, 515_010_000, -1_200_000),
+            (9, 514_990_000, 1_000_000),
+        ];
+        let mut block = string_table(&[""]);
+        block.extend(dense_nodes_group(&dense));
+        let path = write_fixture(
+            "dense",
+            &[header_blob(&["OsmSchema-V0.6", "DenseNodes"]), blob("OSMData", &block, true)],
+        );
+
+        let nodes = nodes_in(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(nodes.iter().map(|node| node.0).collect::<Vec<_>>(), vec![5, 3, 9]);
+        for ((_, lon, lat), (_, expected_lat, expected_lon)) in nodes.iter().zip(&dense) {
+            assert_near(*lon, *expected_lon as f64 * 1e-7);
+            assert_near(*lat, *expected_lat as f64 * 1e-7);
+        }
+    }
+
+    #[test]
+    fn granularity_and_offsets_apply_to_plain_and_dense_nodes() {
+        // Microdegrees, offset by 51 degrees north and 1 degree west
+        let mut plain = varint_field(1, unzigzag(7));
+        plain.extend(varint_field(8, unzigzag(500_000)));
+        plain.extend(varint_field(9, unzigzag(-250_000)));
+        let mut block = string_table(&[""]);
+        block.extend(bytes_field(2, &bytes_field(1, &plain)));
+        block.extend(dense_nodes_group(&[(8, 100_000, 300_000)]));
+        block.extend(varint_field(17, 1000));
+        block.extend(varint_field(19, 51_000_000_000));
+        block.extend(varint_field(20, (-1_000_000_000i64) as u64));
+        let path = write_fixture(
+            "granularity",
+            &[header_blob(&["OsmSchema-V0.6"]), blob("OSMData", &block, false)],
+        );
+
+        let nodes = nodes_in(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!((nodes[0].0, nodes[1].0), (7, 8));
+        assert_near(nodes[0].1, -1.25);
+        assert_near(nodes[0].2, 51.5);
+        assert_near(nodes[1].1, -0.7);
+        assert_near(nodes[1].2, 51.1);
+    }
+
+    #[test]
+    fn reads_nodes_and_ways_from_a_small_file() {
+        let strings = ["", "highway", "footway", "name", "Mill Lane"];
+        let mut nodes_block = string_table(&strings);
+        nodes_block.extend(dense_nodes_group(&[
+            (1, 515_000_000, -1_000_000),
+            (2, 515_000_000, -986_000),
+            (3, 515_009_000, -986_000),
+        ]));
+        let mut way = varint_field(1, 10);
+        way.extend(packed_field(2, &[1, 3]));
+        way.extend(packed_field(3, &[2, 4]));
+        way.extend(packed_field(8, &deltas(&[1, 2, 3, 1])));
+        let mut ways_block = string_table(&strings);
+        ways_block.extend(bytes_field(2, &bytes_field(3, &way)));
+        let path = write_fixture(
+            "small",
+            &[
+                header_blob(&["OsmSchema-V0.6", "DenseNodes"]),
+                blob("OSMData", &nodes_block, true),
+                // Unknown blob types are skipped
+                blob("OSMFuture", b"anything", false),
+                blob("OSMData", &ways_block, false),
+            ],
+        );
+
+        let nodes = nodes_in(&path);
+        let mut way_tags: Vec<Vec<(String, String)>> = vec![];
+        let mut way_refs: Vec<Vec<i64>> = vec![];
+        read_ways(&path, |tags, refs| {
+            let tags = tags
+                .iter()
+                .map(|(key, val)| (key.to_string(), val.to_string()))
+                .collect();
+            way_tags.push(tags);
+            way_refs.push(refs.to_vec());
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(nodes.iter().map(|node| node.0).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(
+            way_tags,
+            vec![vec![
+                ("highway".to_string(), "footway".to_string()),
+                ("name".to_string(), "Mill Lane".to_string())
+            ]]
+        );
+        assert_eq!(way_refs, vec![vec![1, 2, 3, 1]]);
+    }
+
+    #[test]
+    fn rejects_files_needing_unsupported_features() {
+        let path = write_fixture(
+            "history",
+            &[header_blob(&["OsmSchema-V0.6", "HistoricalInformation"])],
+        );
+        let result = read_nodes(&path, |_, _, _| {});
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::shared::FloodfillScores;
+
+/// Travel times to one target destination across the departure window. Percentiles are None
+/// where the destination wasn't reached within the time limit for enough departures
+#[derive(Serialize)]
+pub struct TargetDestinationProfile {
+    pub destination: u32,
+    /// How many of the departure times reached this destination
+    pub reached_count: usize,
+    pub travel_time_min: Option<u16>,
+    pub travel_time_median: Option<u16>,
+    /// In the same order as the percentiles in the request
+    pub travel_time_percentiles: Vec<Option<u16>>,
+}
+
+#[derive(Serialize)]
+pub struct ProfileResult {
+    pub start_node: u32,
+    pub departure_times_count: usize,
+    pub nodes_reached_median: i32,
+    pub scores_min: [i64; 32],
+    pub scores_median: [i64; 32],
+    pub scores_max: [i64; 32],
+    /// One set of scores per percentile in the request, each worked out per subpurpose
+    pub scores_percentiles: Vec<[i64; 32]>,
+    pub target_destinations: Vec<TargetDestinationProfile>,
+}
+
+/// Nearest-rank percentile of values sorted in ascending order
+fn percentile<T: Copy>(sorted: &[T], percentile: u8) -> T {
+    let rank = (percentile as f64 / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Scores and target destination travel times for one start node, from a floodfill for each
+/// departure time in the window
+pub fn summarise_profile(
+    start_node: u32,
+    scores_per_departure: &[FloodfillScores],
+    target_destinations: &[u32],
+    percentiles: &[u8],
+) -> ProfileResult {
+    let departure_times_count = scores_per_departure.len();
+
+    let mut nodes_reached: Vec<i32> = scores_per_departure.iter().map(|scores| scores.0).collect();
+    nodes_reached.sort_unstable();
+
+    let mut scores_min = [0; 32];
+    let mut scores_median = [0; 32];
+    let mut scores_max = [0; 32];
+    let mut scores_percentiles = vec![[0; 32]; percentiles.len()];
+    for subpurpose_ix in 0..32 {
+        let mut subpurpose_scores: Vec<i64> = scores_per_departure
+            .iter()
+            .map(|scores| scores.2[subpurpose_ix])
+            .collect();
+        subpurpose_scores.sort_unstable();
+        scores_min[subpurpose_ix] = subpurpose_scores[0];
+        scores_median[subpurpose_ix] = percentile(&subpurpose_scores, 50);
+        scores_max[subpurpose_ix] = subpurpose_scores[departure_times_count - 1];
+        for (percentile_ix, p) in percentiles.iter().enumerate() {
+            scores_percentiles[percentile_ix][subpurpose_ix] = percentile(&subpurpose_scores, *p);
+        }
+    }
+
+    let mut travel_times_by_destination: HashMap<u32, Vec<u16>> = target_destinations
+        .iter()
+        .map(|destination| (*destination, vec![]))
+        .collect();
+    for scores in scores_per_departure {
+        for (destination, travel_time) in scores.3.iter().zip(&scores.4) {
+            if let Some(travel_times) = travel_times_by_destination.get_mut(destination) {
+                travel_times.push(*travel_time);
+            }
+        }
+    }
+
+    let target_destinations = target_destinations
+        .iter()
+        .map(|destination| {
+            // None sorts first, so unreached departures are put at the slow end by hand
+            let mut travel_times = travel_times_by_destination[destination].clone();
+            travel_times.sort_unstable();
+            let reached_count = travel_times.len();
+            let mut with_unreached: Vec<Option<u16>> = travel_times.into_iter().map(Some).collect();
+            with_unreached.resize(departure_times_count, None);
+
+            TargetDestinationProfile {
+                destination: *destination,
+                reached_count,
+                travel_time_min: with_unreached[0],
+                travel_time_median: percentile(&with_unreached, 50),
+                travel_time_percentiles: percentiles
+                    .iter()
+                    .map(|p| percentile(&with_unreached, *p))
+                    .collect(),
+            }
+        })
+        .collect();
+
+    ProfileResult {
+        start_node,
+        departure_times_count,
+        nodes_reached_median: percentile(&nodes_reached, 50),
+        scores_min,
+        scores_median,
+        scores_max,
+        scores_percentiles,
+        target_destinations,
+    }
+}
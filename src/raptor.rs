@@ -0,0 +1,832 @@
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::mem::size_of;
+
+use serde::Deserialize;
+
+use crate::errors::ConnectivityError;
+use crate::floodfill::check_node_exists;
+use crate::graph_overlay::GraphOverlay;
+use crate::priority_queue::PriorityQueueItem;
+use crate::shared::{Cost, FloodfillOutput, NodeID, TripID, SECONDS_PER_DAY};
+
+/// Which algorithm finds the travel times. Both give the same output shape
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Engine {
+    /// Label-setting search over the combined walk and PT graph
+    #[default]
+    Dijkstra,
+    /// Round-based search over routes and trips, with walking between rounds. Profile requests
+    /// reuse labels from one departure time to the next (rRAPTOR)
+    Raptor,
+}
+
+const UNREACHED: u32 = u32::MAX;
+
+#[derive(Clone, Copy)]
+struct StopTime {
+    arrival: u32,
+    departure: u32,
+}
+
+/// Trips which call at the same stops in the same order and never overtake each other
+struct Route {
+    /// Where its stops start in `route_stops`
+    stops_start: u32,
+    stops_count: u32,
+    /// Where its trips start in `stop_times`. Trips are ordered by departure, each taking
+    /// `stops_count` entries
+    stop_times_start: u32,
+    trips_count: u32,
+    /// Departures without trip IDs, each a trip from one stop to the next
+    unknown_trips: bool,
+}
+
+impl Route {
+    fn stop_time(&self, stop_times: &[StopTime], trip: u32, position: u32) -> StopTime {
+        stop_times[(self.stop_times_start + trip * self.stops_count + position) as usize]
+    }
+
+    /// Binary search over the first `trips_count` trips, which are in departure order at every
+    /// stop as they never overtake. Returns `trips_count` if none depart late enough. Times are
+    /// as timetabled, before any shift onto another service day
+    fn first_trip_departing_at_or_after(
+        &self,
+        stop_times: &[StopTime],
+        position: u32,
+        time: u32,
+        trips_count: u32,
+    ) -> u32 {
+        let (mut low, mut high) = (0, trips_count);
+        while low < high {
+            let middle = (low + high) / 2;
+            if self.stop_time(stop_times, middle, position).departure < time {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        low
+    }
+}
+
+/// The PT graph regrouped into routes for RAPTOR.
+///
+/// Departures sharing a trip ID are chained into one trip; departures without one are each a
+/// trip of their own between two stops, so legacy timetables still work. As in the Dijkstra
+/// engine, riding on from one of those to another leaving the moment it arrives isn't a new
+/// boarding. Like the reverse graph, built once per year and kept alongside the forward graphs
+pub struct Timetable {
+    routes: Vec<Route>,
+    /// Stop index of each stop on each route
+    route_stops: Vec<u32>,
+    /// Whether each stop on each route can be boarded, from the node's PT flag
+    route_stops_boardable: Vec<bool>,
+    stop_times: Vec<StopTime>,
+    stop_nodes: Vec<NodeID>,
+    /// UNREACHED for nodes which aren't stops
+    stop_ix_by_node: Vec<u32>,
+    routes_by_stop_offsets: Vec<u32>,
+    /// (route, position of the stop on the route)
+    routes_by_stop: Vec<(u32, u32)>,
+}
+
+impl Timetable {
+    pub fn approx_size_bytes(&self) -> usize {
+        self.routes.capacity() * size_of::<Route>()
+            + (self.route_stops.capacity()
+                + self.stop_ix_by_node.capacity()
+                + self.routes_by_stop_offsets.capacity())
+                * size_of::<u32>()
+            + self.route_stops_boardable.capacity()
+            + self.stop_times.capacity() * size_of::<StopTime>()
+            + self.stop_nodes.capacity() * size_of::<NodeID>()
+            + self.routes_by_stop.capacity() * size_of::<(u32, u32)>()
+    }
+
+    pub fn from_graph(graph: &GraphOverlay) -> Timetable {
+        let node_count = graph.node_count();
+
+        // Each trip as (leavetime, cost, from, to) hops
+        let mut hops_by_trip: HashMap<TripID, Vec<(u32, u32, u32, u32)>> = HashMap::new();
+        let mut trips: Vec<Vec<(u32, u32, u32, u32)>> = vec![];
+        let mut unknown_trips: Vec<Vec<(u32, u32, u32, u32)>> = vec![];
+        for node in 0..node_count as u32 {
+            for edge in graph.pt_edges(node) {
+                let hop = (edge.leavetime.0, edge.cost.0 as u32, node, edge.to.0);
+                if edge.trip == TripID::UNKNOWN {
+                    unknown_trips.push(vec![hop]);
+                } else {
+                    hops_by_trip.entry(edge.trip).or_default().push(hop);
+                }
+            }
+        }
+        for (_, mut hops) in hops_by_trip {
+            hops.sort_unstable();
+            // A trip which doesn't chain stop to stop is split where it breaks
+            let mut piece = vec![hops[0]];
+            for hop in hops.into_iter().skip(1) {
+                if hop.2 != piece[piece.len() - 1].3 {
+                    trips.push(std::mem::take(&mut piece));
+                }
+                piece.push(hop);
+            }
+            trips.push(piece);
+        }
+
+        // Group trips by stop sequence, keeping unknown trips apart
+        let mut trips_by_stops: HashMap<(Vec<u32>, bool), Vec<Vec<StopTime>>> = HashMap::new();
+        let trips = (trips.into_iter().map(|hops| (hops, false)))
+            .chain(unknown_trips.into_iter().map(|hops| (hops, true)));
+        for (hops, unknown_trips) in trips {
+            let mut stops = vec![hops[0].2];
+            let mut times = vec![StopTime {
+                arrival: hops[0].0,
+                departure: hops[0].0,
+            }];
+            for (ix, hop) in hops.iter().enumerate() {
+                let arrival = hop.0 + hop.1;
+                stops.push(hop.3);
+                times.push(StopTime {
+                    arrival,
+                    departure: hops.get(ix + 1).map_or(arrival, |next| next.0),
+                });
+            }
+            trips_by_stops.entry((stops, unknown_trips)).or_default().push(times);
+        }
+
+        let mut timetable = Timetable {
+            routes: vec![],
+            route_stops: vec![],
+            route_stops_boardable: vec![],
+            stop_times: vec![],
+            stop_nodes: vec![],
+            stop_ix_by_node: vec![UNREACHED; node_count],
+            routes_by_stop_offsets: vec![],
+            routes_by_stop: vec![],
+        };
+        for ((stops, unknown_trips), mut trips) in trips_by_stops {
+            trips.sort_unstable_by_key(|times| times[0].departure);
+            // Trips which would overtake the last trip on every route so far start a new route
+            let mut routes_for_stops: Vec<Vec<Vec<StopTime>>> = vec![];
+            for times in trips {
+                let route = routes_for_stops.iter_mut().find(|route| {
+                    route[route.len() - 1].iter().zip(&times).all(|(earlier, later)| {
+                        earlier.arrival <= later.arrival && earlier.departure <= later.departure
+                    })
+                });
+                match route {
+                    Some(route) => route.push(times),
+                    None => routes_for_stops.push(vec![times]),
+                }
+            }
+            for route_trips in routes_for_stops {
+                timetable.add_route(graph, &stops, route_trips, unknown_trips);
+            }
+        }
+
+        // Routes serving each stop, in compressed form like the reverse graph
+        let mut offsets = vec![0u32; timetable.stop_nodes.len() + 1];
+        for stop in &timetable.route_stops {
+            offsets[*stop as usize + 1] += 1;
+        }
+        for ix in 1..offsets.len() {
+            offsets[ix] += offsets[ix - 1];
+        }
+        let mut cursor = offsets.clone();
+        timetable.routes_by_stop = vec![(0, 0); timetable.route_stops.len()];
+        for (route_ix, route) in timetable.routes.iter().enumerate() {
+            for position in 0..route.stops_count {
+                let stop = timetable.route_stops[(route.stops_start + position) as usize];
+                let slot = &mut cursor[stop as usize];
+                timetable.routes_by_stop[*slot as usize] = (route_ix as u32, position);
+                *slot += 1;
+            }
+        }
+        timetable.routes_by_stop_offsets = offsets;
+        timetable
+    }
+
+    fn add_route(
+        &mut self,
+        graph: &GraphOverlay,
+        stops: &[u32],
+        trips: Vec<Vec<StopTime>>,
+        unknown_trips: bool,
+    ) {
+        self.routes.push(Route {
+            stops_start: self.route_stops.len() as u32,
+            stops_count: stops.len() as u32,
+            stop_times_start: self.stop_times.len() as u32,
+            trips_count: trips.len() as u32,
+            unknown_trips,
+        });
+        for node in stops {
+            let stop_ix = &mut self.stop_ix_by_node[*node as usize];
+            if *stop_ix == UNREACHED {
+                *stop_ix = self.stop_nodes.len() as u32;
+                self.stop_nodes.push(NodeID(*node));
+            }
+            self.route_stops.push(*stop_ix);
+            self.route_stops_boardable
+                .push(graph.walk_edges(*node)[0].cost == Cost(1));
+        }
+        for times in trips {
+            self.stop_times.extend(times);
+        }
+    }
+
+    pub fn stops_count(&self) -> usize {
+        self.stop_nodes.len()
+    }
+
+    fn routes_serving(&self, stop: u32) -> &[(u32, u32)] {
+        &self.routes_by_stop[self.routes_by_stop_offsets[stop as usize] as usize
+            ..self.routes_by_stop_offsets[stop as usize + 1] as usize]
+    }
+}
+
+/// Labels kept from one departure time to the next in a profile search
+struct RaptorLabels {
+    /// Earliest arrival at each node reached with at most that many boardings, by round. A round
+    /// only holds the nodes it reached sooner than every earlier round did
+    best_arrivals: Vec<HashMap<u32, u32>>,
+    /// Earliest arrival at each stop with at most that many boardings, by stop index
+    arrivals_by_round: Vec<Vec<u32>>,
+    marked: Vec<bool>,
+    marked_stops: Vec<u32>,
+}
+
+impl RaptorLabels {
+    /// Earliest arrival at the node with at most `round` boardings
+    fn best_arrival(&self, round: usize, node: u32) -> Option<u32> {
+        self.best_arrivals[..=round]
+            .iter()
+            .filter_map(|arrivals| arrivals.get(&node).copied())
+            .min()
+    }
+
+    /// Labels from a later departure time can't stop an earlier one reaching somewhere in fewer
+    /// boardings, which later rounds might need to catch a trip in time
+    fn improve(&mut self, round: usize, node: u32, arrival: u32) -> bool {
+        if let Some(best) = self.best_arrival(round, node) {
+            if arrival >= best {
+                return false;
+            }
+        }
+        self.best_arrivals[round].insert(node, arrival);
+        true
+    }
+
+    fn mark(&mut self, round: usize, stop: u32, arrival: u32) {
+        let round_arrival = &mut self.arrivals_by_round[round][stop as usize];
+        if arrival < *round_arrival {
+            *round_arrival = arrival;
+        }
+        if !self.marked[stop as usize] {
+            self.marked[stop as usize] = true;
+            self.marked_stops.push(stop);
+        }
+    }
+}
+
+/// Same output as get_travel_times, found with RAPTOR. `max_boardings` is the number of rounds
+pub fn get_travel_times_raptor(
+    graph: &GraphOverlay,
+    timetable: &Timetable,
+    start: NodeID,
+    trip_start_seconds: i32,
+    init_travel_time: Cost,
+    time_limit: Cost,
+    max_boardings: u8,
+) -> Result<FloodfillOutput, ConnectivityError> {
+    let mut results = get_travel_times_raptor_profile(
+        graph,
+        timetable,
+        start,
+        &[trip_start_seconds],
+        init_travel_time,
+        time_limit,
+        max_boardings,
+    )?;
+    Ok(results.pop().unwrap())
+}
+
+/// One get_travel_times_raptor output per departure time, in the same order. Departure times
+/// must be ascending: they're searched latest first, and each search starts from the arrival
+/// times already found, as leaving later and waiting is always an option
+pub fn get_travel_times_raptor_profile(
+    graph: &GraphOverlay,
+    timetable: &Timetable,
+    start: NodeID,
+    departure_times: &[i32],
+    init_travel_time: Cost,
+    time_limit: Cost,
+    max_boardings: u8,
+) -> Result<Vec<FloodfillOutput>, ConnectivityError> {
+    check_node_exists(start, graph.node_count())?;
+    let mut labels = RaptorLabels {
+        best_arrivals: vec![HashMap::new()],
+        arrivals_by_round: vec![vec![UNREACHED; timetable.stops_count()]],
+        marked: vec![false; timetable.stops_count()],
+        marked_stops: vec![],
+    };
+    let mut results = Vec::with_capacity(departure_times.len());
+
+    for departure_time in departure_times.iter().rev() {
+        let departure_time = *departure_time as u32;
+        let arrival_limit = departure_time + time_limit.0 as u32;
+        // catch where start node is further from centroid than the time limit
+        if init_travel_time >= time_limit {
+            results.push((start.0, vec![], vec![]));
+            continue;
+        }
+
+        let start_arrival = departure_time + init_travel_time.0 as u32;
+        if labels.improve(0, start.0, start_arrival) {
+            walk_transfers(graph, timetable, &mut labels, 0, &[start], arrival_limit);
+        }
+
+        let mut round = 1;
+        while !labels.marked_stops.is_empty() && round <= max_boardings as usize {
+            if labels.arrivals_by_round.len() <= round {
+                labels
+                    .arrivals_by_round
+                    .push(vec![UNREACHED; timetable.stops_count()]);
+                labels.best_arrivals.push(HashMap::new());
+            }
+            let alighted_at = scan_routes(timetable, &mut labels, round, arrival_limit);
+            walk_transfers(graph, timetable, &mut labels, round, &alighted_at, arrival_limit);
+            round += 1;
+        }
+        for stop in labels.marked_stops.drain(..) {
+            labels.marked[stop as usize] = false;
+        }
+
+        // Rounds past max_boardings are never run, so every round's labels are allowed
+        let mut best_arrivals: HashMap<u32, u32> = HashMap::new();
+        for arrivals in &labels.best_arrivals {
+            for (node, arrival) in arrivals {
+                best_arrivals
+                    .entry(*node)
+                    .and_modify(|best| *best = (*best).min(*arrival))
+                    .or_insert(*arrival);
+            }
+        }
+        // In arrival order, as get_travel_times would settle them
+        let mut reached: Vec<(u32, u32)> = best_arrivals
+            .iter()
+            .filter(|(_, arrival)| **arrival < arrival_limit)
+            .map(|(node, arrival)| (*arrival - departure_time, *node))
+            .collect();
+        reached.sort_unstable();
+        results.push((
+            start.0,
+            reached.iter().map(|(_, node)| *node).collect(),
+            reached.iter().map(|(travel_time, _)| *travel_time as u16).collect(),
+        ));
+    }
+
+    results.reverse();
+    Ok(results)
+}
+
+/// Rides every route through a stop marked last round, and on from departures without trip IDs
+/// to any leaving the moment they arrive. Returns the nodes alighted at with a better arrival
+/// time, which are marked for this round
+fn scan_routes(
+    timetable: &Timetable,
+    labels: &mut RaptorLabels,
+    round: usize,
+    arrival_limit: u32,
+) -> Vec<NodeID> {
+    // Earliest marked position on each route
+    let mut routes_to_scan: HashMap<u32, u32> = HashMap::new();
+    for stop in labels.marked_stops.drain(..) {
+        labels.marked[stop as usize] = false;
+        for (route_ix, position) in timetable.routes_serving(stop) {
+            routes_to_scan
+                .entry(*route_ix)
+                .and_modify(|earliest| *earliest = (*earliest).min(*position))
+                .or_insert(*position);
+        }
+    }
+
+    let mut alighted_at = vec![];
+    // Stops and arrival times from unknown trips, where the vehicle might carry on
+    let mut riding_on: Vec<(u32, u32)> = vec![];
+    for (route_ix, first_position) in routes_to_scan {
+        let route = &timetable.routes[route_ix as usize];
+        let mut trip: Option<TripOnDay> = None;
+        for position in first_position..route.stops_count {
+            let route_stop_ix = (route.stops_start + position) as usize;
+            let stop = timetable.route_stops[route_stop_ix];
+
+            if let Some(trip) = trip {
+                let arrival = trip.stop_time(route, &timetable.stop_times, position).arrival;
+                let node = timetable.stop_nodes[stop as usize];
+                if arrival < arrival_limit && labels.improve(round, node.0, arrival) {
+                    labels.mark(round, stop, arrival);
+                    alighted_at.push(node);
+                }
+                if arrival < arrival_limit && route.unknown_trips {
+                    riding_on.push((stop, arrival));
+                }
+            }
+
+            // Catch an earlier trip if an earlier round got here in time for one
+            let previous_arrival = labels.arrivals_by_round[..round]
+                .iter()
+                .map(|arrivals| arrivals[stop as usize])
+                .min()
+                .unwrap();
+            if previous_arrival == UNREACHED || !timetable.route_stops_boardable[route_stop_ix] {
+                continue;
+            }
+            let earliest_catchable = earliest_trip_on_any_day(
+                timetable,
+                route,
+                position,
+                previous_arrival,
+                arrival_limit,
+            );
+            if let Some(earliest_catchable) = earliest_catchable {
+                let departure_of = |trip: TripOnDay| {
+                    trip.stop_time(route, &timetable.stop_times, position).departure
+                };
+                let earlier = match trip {
+                    Some(trip) => departure_of(earliest_catchable) < departure_of(trip),
+                    None => true,
+                };
+                if earlier {
+                    trip = Some(earliest_catchable);
+                }
+            }
+        }
+    }
+    ride_on_unknown_trips(timetable, labels, round, arrival_limit, riding_on, &mut alighted_at);
+    alighted_at
+}
+
+/// Follows departures without trip IDs leaving a stop exactly when another arrived, counting
+/// them as the same vehicle like get_travel_times_with_boardings does. Rides on whether or not
+/// a stop was reached sooner, as a later stop might still be
+fn ride_on_unknown_trips(
+    timetable: &Timetable,
+    labels: &mut RaptorLabels,
+    round: usize,
+    arrival_limit: u32,
+    mut riding_on: Vec<(u32, u32)>,
+    alighted_at: &mut Vec<NodeID>,
+) {
+    let mut ridden: HashSet<(u32, u32, u32)> = HashSet::new();
+    while let Some((stop, arrival)) = riding_on.pop() {
+        for (route_ix, position) in timetable.routes_serving(stop) {
+            let route = &timetable.routes[*route_ix as usize];
+            if !route.unknown_trips || *position != 0 {
+                continue;
+            }
+            for day in 0..3 {
+                // The next day's trips can only be used by searches running past midnight
+                if day == 2 && arrival_limit <= SECONDS_PER_DAY {
+                    continue;
+                }
+                let shifted = (arrival + SECONDS_PER_DAY).checked_sub(day * SECONDS_PER_DAY);
+                let Some(time_on_day) = shifted else {
+                    continue;
+                };
+                let first_trip = route.first_trip_departing_at_or_after(
+                    &timetable.stop_times,
+                    0,
+                    time_on_day,
+                    route.trips_count,
+                );
+                for trip in first_trip..route.trips_count {
+                    let trip = TripOnDay { trip, day };
+                    if trip.stop_time(route, &timetable.stop_times, 0).departure != arrival {
+                        break;
+                    }
+                    if !ridden.insert((*route_ix, trip.trip, day)) {
+                        continue;
+                    }
+                    let next_arrival = trip.stop_time(route, &timetable.stop_times, 1).arrival;
+                    if next_arrival >= arrival_limit {
+                        continue;
+                    }
+                    let next_stop = timetable.route_stops[route.stops_start as usize + 1];
+                    let node = timetable.stop_nodes[next_stop as usize];
+                    if labels.improve(round, node.0, next_arrival) {
+                        labels.mark(round, next_stop, next_arrival);
+                        alighted_at.push(node);
+                    }
+                    riding_on.push((next_stop, next_arrival));
+                }
+            }
+        }
+    }
+}
+
+/// A trip on the service day before the request's (day 0), the request's own (1) or the one
+/// after (2). Every day runs the same timetable, shifted by a day
+#[derive(Clone, Copy)]
+struct TripOnDay {
+    trip: u32,
+    day: u32,
+}
+
+impl TripOnDay {
+    /// Times in seconds past midnight on the request's service day. Trips from the day before
+    /// are only caught after midnight, so the times can't go negative
+    fn stop_time(&self, route: &Route, stop_times: &[StopTime], position: u32) -> StopTime {
+        let stop_time = route.stop_time(stop_times, self.trip, position);
+        let shift = |time: u32| time + self.day * SECONDS_PER_DAY - SECONDS_PER_DAY;
+        StopTime {
+            arrival: shift(stop_time.arrival),
+            departure: shift(stop_time.departure),
+        }
+    }
+}
+
+/// The first trip leaving the route's stop at or after `time`, from any of the three days
+fn earliest_trip_on_any_day(
+    timetable: &Timetable,
+    route: &Route,
+    position: u32,
+    time: u32,
+    arrival_limit: u32,
+) -> Option<TripOnDay> {
+    let mut earliest: Option<(TripOnDay, u32)> = None;
+    for day in 0..3 {
+        // The next day's trips can only be used by searches running past midnight
+        if day == 2 && arrival_limit <= SECONDS_PER_DAY {
+            continue;
+        }
+        let time_on_day = (time + SECONDS_PER_DAY).saturating_sub(day * SECONDS_PER_DAY);
+        let trip = route.first_trip_departing_at_or_after(
+            &timetable.stop_times,
+            position,
+            time_on_day,
+            route.trips_count,
+        );
+        if trip == route.trips_count {
+            continue;
+        }
+        let trip = TripOnDay { trip, day };
+        let departure = trip.stop_time(route, &timetable.stop_times, position).departure;
+        let earlier = match earliest {
+            Some((_, earliest_departure)) => departure < earliest_departure,
+            None => true,
+        };
+        if earlier {
+            earliest = Some((trip, departure));
+        }
+    }
+    earliest.map(|(trip, _)| trip)
+}
+
+/// Walks on from newly reached nodes, marking any stops reached sooner than before
+fn walk_transfers(
+    graph: &GraphOverlay,
+    timetable: &Timetable,
+    labels: &mut RaptorLabels,
+    round: usize,
+    sources: &[NodeID],
+    arrival_limit: u32,
+) {
+    let mut queue: BinaryHeap<PriorityQueueItem<u32, NodeID>> = BinaryHeap::new();
+    for node in sources {
+        let Some(&arrival) = labels.best_arrivals[round].get(&node.0) else {
+            continue;
+        };
+        queue.push(PriorityQueueItem {
+            cost: arrival,
+            value: *node,
+        });
+        let stop = timetable.stop_ix_by_node[node.0 as usize];
+        if stop != UNREACHED {
+            labels.mark(round, stop, arrival);
+        }
+    }
+
+    while let Some(current) = queue.pop() {
+        match labels.best_arrivals[round].get(&current.value.0) {
+            Some(&best) if current.cost <= best => {}
+            _ => continue,
+        }
+        // skip 1st edge as it has info on whether node also has a PT service
+        for edge in &graph.walk_edges(current.value.0)[1..] {
+            let arrival = current.cost + edge.cost.0 as u32;
+            if arrival < arrival_limit && labels.improve(round, edge.to.0, arrival) {
+                queue.push(PriorityQueueItem {
+                    cost: arrival,
+                    value: edge.to,
+                });
+                let stop = timetable.stop_ix_by_node[edge.to.0 as usize];
+                if stop != UNREACHED {
+                    labels.mark(round, stop, arrival);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use smallvec::{smallvec, SmallVec};
+
+    use super::*;
+    use crate::floodfill::{get_travel_times, get_travel_times_with_boardings};
+    use crate::graph_cache::YearData;
+    use crate::shared::{EdgePT, EdgeWalk, LeavingTime};
+
+    const TIME_LIMIT: Cost = Cost(3600);
+
+    /// Stops A (0), B (1), C (2) and X (3), and a street node S (4) 10s walk from A. From A,
+    /// trip 1 goes straight to B at 200 and trip 2 to X at 160, with trip 3 on to B at 200 too.
+    /// Only trip 4 from B, leaving at 250, reaches C
+    fn fixture() -> GraphOverlay {
+        let has_pt = EdgeWalk {
+            to: NodeID(0),
+            cost: Cost(1),
+        };
+        let no_pt = EdgeWalk {
+            to: NodeID(0),
+            cost: Cost(0),
+        };
+        let walk = |to: u32| EdgeWalk {
+            to: NodeID(to),
+            cost: Cost(10),
+        };
+        let ride = |leavetime: u32, cost: u16, to: u32, trip: u32| EdgePT {
+            leavetime: LeavingTime(leavetime),
+            cost: Cost(cost),
+            to: NodeID(to),
+            trip: TripID(trip),
+        };
+        let graph_walk: Vec<SmallVec<[EdgeWalk; 4]>> = vec![
+            smallvec![has_pt, walk(4)],
+            smallvec![has_pt],
+            smallvec![has_pt],
+            smallvec![has_pt],
+            smallvec![no_pt, walk(0)],
+        ];
+        let graph_pt: Vec<SmallVec<[EdgePT; 4]>> = vec![
+            smallvec![ride(100, 100, 1, 1), ride(150, 10, 3, 2)],
+            smallvec![ride(250, 50, 2, 4)],
+            smallvec![],
+            smallvec![ride(170, 30, 1, 3)],
+            smallvec![],
+        ];
+        let year = YearData::new(graph_walk, graph_pt, vec![vec![]; 5], 0, None);
+        GraphOverlay::new(Arc::new(year), None)
+    }
+
+    /// Legacy departures without trip IDs between stops A (0), B (1), C (2) and D (3): A to B
+    /// arrives at 150, just as one leaves B for C, which arrives as one leaves C for D. Another
+    /// leaves B for D at 180, which is a new boarding
+    fn legacy_fixture() -> GraphOverlay {
+        let has_pt = EdgeWalk {
+            to: NodeID(0),
+            cost: Cost(1),
+        };
+        let ride = |leavetime: u32, cost: u16, to: u32| EdgePT {
+            leavetime: LeavingTime(leavetime),
+            cost: Cost(cost),
+            to: NodeID(to),
+            trip: TripID::UNKNOWN,
+        };
+        let graph_walk: Vec<SmallVec<[EdgeWalk; 4]>> = vec![smallvec![has_pt]; 4];
+        let graph_pt: Vec<SmallVec<[EdgePT; 4]>> = vec![
+            smallvec![ride(100, 50, 1)],
+            smallvec![ride(150, 50, 2), ride(180, 30, 3)],
+            smallvec![ride(200, 60, 3)],
+            smallvec![],
+        ];
+        let year = YearData::new(graph_walk, graph_pt, vec![vec![]; 4], 0, None);
+        GraphOverlay::new(Arc::new(year), None)
+    }
+
+    fn sorted_pairs(output: &FloodfillOutput) -> Vec<(u32, u16)> {
+        let mut pairs: Vec<(u32, u16)> = output.1.iter().copied().zip(output.2.clone()).collect();
+        pairs.sort_unstable();
+        pairs
+    }
+
+    #[test]
+    fn raptor_matches_dijkstra_without_boarding_limit() {
+        let graph = fixture();
+        let timetable = Timetable::from_graph(&graph);
+        for start in 0..5 {
+            for trip_start_seconds in [0, 90, 140, 165] {
+                let raptor = get_travel_times_raptor(
+                    &graph,
+                    &timetable,
+                    NodeID(start),
+                    trip_start_seconds,
+                    Cost(0),
+                    TIME_LIMIT,
+                    u8::MAX,
+                )
+                .unwrap();
+                let dijkstra = get_travel_times(
+                    &graph,
+                    NodeID(start),
+                    trip_start_seconds,
+                    Cost(0),
+                    TIME_LIMIT,
+                    None,
+                )
+                .unwrap();
+                assert_eq!(
+                    sorted_pairs(&raptor),
+                    sorted_pairs(&dijkstra),
+                    "start {} at {}",
+                    start,
+                    trip_start_seconds
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn raptor_profile_keeps_journeys_with_fewer_boardings() {
+        let graph = fixture();
+        let timetable = Timetable::from_graph(&graph);
+        let departure_times = [90, 140];
+        let max_boardings = 2;
+        let profile = get_travel_times_raptor_profile(
+            &graph,
+            &timetable,
+            NodeID(4),
+            &departure_times,
+            Cost(0),
+            TIME_LIMIT,
+            max_boardings,
+        )
+        .unwrap();
+
+        for (trip_start_seconds, raptor) in departure_times.iter().zip(&profile) {
+            let dijkstra = get_travel_times_with_boardings(
+                &graph,
+                NodeID(4),
+                *trip_start_seconds,
+                Cost(0),
+                TIME_LIMIT,
+                max_boardings,
+                Cost(0),
+            )
+            .unwrap();
+            assert_eq!(sorted_pairs(raptor), sorted_pairs(&dijkstra));
+        }
+        // Leaving at 90 catches trip 1 then trip 4, but leaving at 140 needs three trips
+        assert!(sorted_pairs(&profile[0]).contains(&(2, 210)));
+        assert!(!profile[1].1.contains(&2));
+    }
+
+    #[test]
+    fn raptor_rides_on_legacy_departures_like_dijkstra() {
+        let graph = legacy_fixture();
+        let timetable = Timetable::from_graph(&graph);
+        for max_boardings in [1, 2] {
+            for start in 0..4 {
+                for trip_start_seconds in [0, 90, 150] {
+                    let raptor = get_travel_times_raptor(
+                        &graph,
+                        &timetable,
+                        NodeID(start),
+                        trip_start_seconds,
+                        Cost(0),
+                        TIME_LIMIT,
+                        max_boardings,
+                    )
+                    .unwrap();
+                    let dijkstra = get_travel_times_with_boardings(
+                        &graph,
+                        NodeID(start),
+                        trip_start_seconds,
+                        Cost(0),
+                        TIME_LIMIT,
+                        max_boardings,
+                        Cost(0),
+                    )
+                    .unwrap();
+                    assert_eq!(
+                        sorted_pairs(&raptor),
+                        sorted_pairs(&dijkstra),
+                        "start {} at {} with {} boardings",
+                        start,
+                        trip_start_seconds,
+                        max_boardings
+                    );
+                }
+            }
+        }
+        // One boarding at A rides through B and C to D
+        let raptor =
+            get_travel_times_raptor(&graph, &timetable, NodeID(0), 90, Cost(0), TIME_LIMIT, 1)
+                .unwrap();
+        assert!(sorted_pairs(&raptor).contains(&(3, 170)));
+    }
+}
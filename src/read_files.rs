@@ -3,69 +3,43 @@ use fs_err::File;
 use serde::de::DeserializeOwned;
 use smallvec::SmallVec;
 use std::io::{BufReader, BufWriter};
+use std::path::Path;
 use std::time::Instant;
 
-use crate::shared::{EdgePT, EdgeWalk};
+use crate::errors::ConnectivityError;
+use crate::service_days::TripServiceDays;
+use crate::shared::{EdgePT, EdgeWalk, GraphPT, GraphWalk, NodeValues2d};
 
-pub fn read_sparse_node_values_2d_serial(year: i32) -> Vec<Vec<[i32;2]>> { 
+pub fn read_sparse_node_values_2d_serial(year: i32) -> Result<NodeValues2d, ConnectivityError> {
     let now = Instant::now();
-    let sparse_node_values_2d: Vec<Vec<[i32;2]>> = deserialize_bincoded_file(&format!("sparse_node_values_6am_{year}_2d"));
+    let sparse_node_values_2d: NodeValues2d = deserialize_bincoded_file(&format!("sparse_node_values_6am_{year}_2d"))?;
     println!("Serial loading took {:?}", now.elapsed());
-    return sparse_node_values_2d
+    Ok(sparse_node_values_2d)
 }
 
 
-pub fn read_files_parallel_excluding_node_values(
-    year: i32,
-) -> (
-    Vec<SmallVec<[EdgeWalk; 4]>>,
-    Vec<SmallVec<[EdgePT; 4]>>,
-    u32,
-) {
-    let now = Instant::now();
-
-    let (graph_walk, graph_pt) = rayon::join(
-                || {
-                    deserialize_bincoded_file::<Vec<SmallVec<[EdgeWalk; 4]>>>(&format!(
-                        "p1_main_nodes_vector_6am_{year}"
-                    ))
-                },
-                || {
-                    deserialize_bincoded_file::<Vec<SmallVec<[EdgePT; 4]>>>(&format!(
-                        "p2_main_nodes_vector_6am_{year}"
-                    ))
-                },
-            );
+/// Whether a year has been serialised, judging by its walk graph
+pub fn year_exists(year: i32) -> bool {
+    Path::new(&format!("serialised_data/p1_main_nodes_vector_6am_{year}.bin")).exists()
+}
 
-    let node_values_padding_row_count: u32 =
-        deserialize_bincoded_file(&format!("node_values_padding_row_count_6am_{year}"));
 
-    println!(
-        "Parallel loading for files excluding travel time relationships took {:?}",
-        now.elapsed()
-    );
-    (
-        graph_walk,
-        graph_pt,
-        node_values_padding_row_count,
-    )
+/// The days each trip runs on, if the year was serialised with a service calendar
+pub fn read_trip_service_days(year: i32) -> Result<Option<TripServiceDays>, ConnectivityError> {
+    let filename = format!("trip_service_days_6am_{year}");
+    if !Path::new(&format!("serialised_data/{filename}.bin")).exists() {
+        return Ok(None);
+    }
+    Ok(Some(TripServiceDays(deserialize_bincoded_file(&filename)?)))
 }
 
 
-pub fn read_files_parallel_excluding_travel_time_relationships_and_subpurpose_lookup(
+pub fn read_files_parallel_excluding_node_values(
     year: i32,
-) -> (
-    Vec<i32>,
-    Vec<SmallVec<[EdgeWalk; 4]>>,
-    Vec<SmallVec<[EdgePT; 4]>>,
-    u32,
-) {
+) -> Result<(GraphWalk, GraphPT, u32), ConnectivityError> {
     let now = Instant::now();
 
-    let (node_values_1d, (graph_walk, graph_pt)) = rayon::join(
-        || deserialize_bincoded_file::<Vec<i32>>(&format!("padded_node_values_6am_{year}")),
-        || {
-            rayon::join(
+    let (graph_walk, graph_pt) = rayon::join(
                 || {
                     deserialize_bincoded_file::<Vec<SmallVec<[EdgeWalk; 4]>>>(&format!(
                         "p1_main_nodes_vector_6am_{year}"
@@ -73,62 +47,61 @@ pub fn read_files_parallel_excluding_travel_time_relationships_and_subpurpose_lo
                 },
                 || {
                     deserialize_bincoded_file::<Vec<SmallVec<[EdgePT; 4]>>>(&format!(
-                        "p2_main_nodes_vector_6am_{year}"
+                        "p2_departures_vector_6am_{year}"
                     ))
                 },
-            )
-        },
-    );
+            );
 
     let node_values_padding_row_count: u32 =
-        deserialize_bincoded_file(&format!("node_values_padding_row_count_6am_{year}"));
+        deserialize_bincoded_file(&format!("node_values_padding_row_count_6am_{year}"))?;
 
     println!(
         "Parallel loading for files excluding travel time relationships took {:?}",
         now.elapsed()
     );
-    (
-        node_values_1d,
-        graph_walk,
-        graph_pt,
+    Ok((
+        graph_walk?,
+        graph_pt?,
         node_values_padding_row_count,
-    )
+    ))
 }
 
-pub fn read_small_files_serial() -> (Vec<i32>, Vec<i32>, Vec<i32>, Vec<i32>, [i8; 32]) {
+
+/// Returns the travel time relationships from each of the files, which are listed in the time
+/// bands manifest, and the subpurpose to purpose lookup
+pub fn read_small_files_serial(
+    travel_time_relationships_files: &[String],
+) -> Result<(Vec<Vec<i32>>, [i8; 32]), ConnectivityError> {
     let now = Instant::now();
 
-    let travel_time_relationships_7: Vec<i32> =
-        deserialize_bincoded_file("travel_time_relationships_7");
-    let travel_time_relationships_10: Vec<i32> =
-        deserialize_bincoded_file("travel_time_relationships_10");
-    let travel_time_relationships_16: Vec<i32> =
-        deserialize_bincoded_file("travel_time_relationships_16");
-    let travel_time_relationships_19: Vec<i32> =
-        deserialize_bincoded_file("travel_time_relationships_19");
+    let travel_time_relationships_all = travel_time_relationships_files
+        .iter()
+        .map(|filename| deserialize_bincoded_file::<Vec<i32>>(filename))
+        .collect::<Result<Vec<_>, _>>()?;
     let subpurpose_purpose_lookup: [i8; 32] =
-        deserialize_bincoded_file("subpurpose_purpose_lookup");
+        deserialize_bincoded_file("subpurpose_purpose_lookup")?;
 
     println!("Serial loading took {:?}", now.elapsed());
-    (
-        travel_time_relationships_7,
-        travel_time_relationships_10,
-        travel_time_relationships_16,
-        travel_time_relationships_19,
-        subpurpose_purpose_lookup,
-    )
+    Ok((travel_time_relationships_all, subpurpose_purpose_lookup))
 }
 
-pub fn deserialize_bincoded_file<T: DeserializeOwned>(filename: &str) -> T {
+pub fn deserialize_bincoded_file<T: DeserializeOwned>(filename: &str) -> Result<T, ConnectivityError> {
     let path = format!("serialised_data/{}.bin", filename);
-    let file = BufReader::new(File::open(path).unwrap());
-    bincode::deserialize_from(file).unwrap()
+    let file = BufReader::new(File::open(&path).map_err(|err| ConnectivityError::DataFile {
+        path: path.clone(),
+        message: err.to_string(),
+    })?);
+    bincode::deserialize_from(file).map_err(|err| ConnectivityError::DataFile {
+        path,
+        message: err.to_string(),
+    })
 }
 
 pub fn create_graph_walk_len(year: i32) {
     let graph_walk = deserialize_bincoded_file::<Vec<SmallVec<[EdgeWalk; 4]>>>(&format!(
         "p1_main_nodes_vector_6am_{year}"
-    ));
+    ))
+    .unwrap();
 
     let graph_walk_len = graph_walk.len();
 
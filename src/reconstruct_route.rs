@@ -0,0 +1,145 @@
+use serde::Serialize;
+
+use crate::floodfill::{Predecessor, Predecessors};
+use crate::shared::TripID;
+
+/// Times are seconds past midnight
+#[derive(Serialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum Leg {
+    Walk {
+        /// Every node walked through, including the first and last
+        nodes: Vec<u32>,
+        departure_time_seconds: u32,
+        arrival_time_seconds: u32,
+    },
+    #[serde(rename = "pt")]
+    PT {
+        /// None when the timetable doesn't identify trips
+        trip: Option<u32>,
+        boarding_node: u32,
+        alighting_node: u32,
+        arrival_at_stop_time_seconds: u32,
+        departure_time_seconds: u32,
+        wait_seconds: u32,
+        in_vehicle_seconds: u32,
+        arrival_time_seconds: u32,
+    },
+}
+
+#[derive(Serialize)]
+pub struct Route {
+    pub destination: u32,
+    pub travel_time_seconds: u16,
+    pub legs: Vec<Leg>,
+}
+
+#[derive(Serialize)]
+pub struct StartNodeRoutes {
+    pub start_node: u32,
+    /// Only for target destinations reached within the time limit
+    pub routes: Vec<Route>,
+}
+
+/// Follows predecessors back from the destination, then turns the path into legs. Consecutive
+/// walk edges are merged into one leg, as are consecutive PT hops on the same trip. Hops on
+/// timetables without trip IDs are each their own leg
+pub fn reconstruct_route(
+    predecessors: &Predecessors,
+    destination: u32,
+    trip_start_seconds: i32,
+) -> Option<Route> {
+    let (travel_time, _) = predecessors.reached.get(&destination)?;
+
+    // (node, cost to reach it, how it was reached), destination first
+    let mut path_reversed = vec![];
+    let mut node = destination;
+    loop {
+        let (cost, predecessor) = predecessors.reached[&node];
+        path_reversed.push((node, cost, predecessor));
+        match predecessor {
+            Predecessor::Start => break,
+            Predecessor::Walk { from } | Predecessor::PT { from, .. } => node = from.0,
+        }
+    }
+
+    let seconds_past_midnight = |cost: u16| trip_start_seconds as u32 + cost as u32;
+    let mut legs: Vec<Leg> = vec![];
+    let mut previous_cost = path_reversed.last().unwrap().1;
+
+    for (node, cost, predecessor) in path_reversed.into_iter().rev() {
+        match predecessor {
+            Predecessor::Start => {}
+            Predecessor::Walk { from } => {
+                if let Some(Leg::Walk {
+                    nodes,
+                    arrival_time_seconds,
+                    ..
+                }) = legs.last_mut()
+                {
+                    nodes.push(node);
+                    *arrival_time_seconds = seconds_past_midnight(cost.0);
+                } else {
+                    legs.push(Leg::Walk {
+                        nodes: vec![from.0, node],
+                        departure_time_seconds: seconds_past_midnight(previous_cost.0),
+                        arrival_time_seconds: seconds_past_midnight(cost.0),
+                    });
+                }
+            }
+            Predecessor::PT {
+                from,
+                leavetime,
+                trip,
+            } => {
+                let arrival_at_stop_time_seconds = seconds_past_midnight(previous_cost.0);
+                let arrival_time_seconds = seconds_past_midnight(cost.0);
+                let trip = if trip == TripID::UNKNOWN {
+                    None
+                } else {
+                    Some(trip.0)
+                };
+
+                // Staying on board: extend the previous leg rather than starting a new one
+                if let Some(Leg::PT {
+                    trip: previous_trip,
+                    alighting_node,
+                    departure_time_seconds,
+                    in_vehicle_seconds,
+                    arrival_time_seconds: previous_arrival_time_seconds,
+                    ..
+                }) = legs.last_mut()
+                {
+                    if trip.is_some()
+                        && *previous_trip == trip
+                        && *previous_arrival_time_seconds <= leavetime.0
+                    {
+                        *alighting_node = node;
+                        *in_vehicle_seconds = arrival_time_seconds - *departure_time_seconds;
+                        *previous_arrival_time_seconds = arrival_time_seconds;
+                        previous_cost = cost;
+                        continue;
+                    }
+                }
+
+                legs.push(Leg::PT {
+                    trip,
+                    boarding_node: from.0,
+                    alighting_node: node,
+                    arrival_at_stop_time_seconds,
+                    departure_time_seconds: leavetime.0,
+                    wait_seconds: leavetime.0 - arrival_at_stop_time_seconds,
+                    in_vehicle_seconds: arrival_time_seconds - leavetime.0,
+                    arrival_time_seconds,
+                });
+            }
+        }
+        previous_cost = cost;
+    }
+
+    Some(Route {
+        destination,
+        travel_time_seconds: travel_time.0,
+        legs,
+    })
+}
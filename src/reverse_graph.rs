@@ -0,0 +1,110 @@
+use std::mem::size_of;
+
+use crate::graph_overlay::GraphOverlay;
+use crate::shared::{Cost, EdgePT, EdgeWalk, LeavingTime, NodeID, TripID};
+
+/// The walk and PT graphs transposed, for searching backwards from a destination.
+///
+/// Built once per year and kept alongside the forward graphs, so it's stored as flat arrays with
+/// offsets per node rather than a SmallVec per node: most nodes have few incoming edges and this
+/// takes a fraction of the memory
+pub struct ReverseGraph {
+    walk_offsets: Vec<u32>,
+    /// `to` is the node the edge comes from
+    walk_incoming: Vec<EdgeWalk>,
+    pt_offsets: Vec<u32>,
+    /// `to` is the stop the departure leaves from. Ordered by arrival time (leavetime + cost)
+    pt_incoming: Vec<EdgePT>,
+}
+
+impl ReverseGraph {
+    pub fn approx_size_bytes(&self) -> usize {
+        (self.walk_offsets.capacity() + self.pt_offsets.capacity()) * size_of::<u32>()
+            + self.walk_incoming.capacity() * size_of::<EdgeWalk>()
+            + self.pt_incoming.capacity() * size_of::<EdgePT>()
+    }
+
+    pub fn from_graph(graph: &GraphOverlay) -> ReverseGraph {
+        let node_count = graph.node_count();
+
+        let mut walk_offsets = vec![0u32; node_count + 1];
+        let mut pt_offsets = vec![0u32; node_count + 1];
+        for node in 0..node_count as u32 {
+            // skip 1st edge as it has info on whether node also has a PT service
+            for edge in graph.walk_edges(node).iter().skip(1) {
+                walk_offsets[edge.to.0 as usize + 1] += 1;
+            }
+            for edge in graph.pt_edges(node) {
+                pt_offsets[edge.to.0 as usize + 1] += 1;
+            }
+        }
+        for ix in 1..=node_count {
+            walk_offsets[ix] += walk_offsets[ix - 1];
+            pt_offsets[ix] += pt_offsets[ix - 1];
+        }
+
+        // Every placeholder is overwritten below
+        let placeholder_walk = EdgeWalk {
+            to: NodeID(0),
+            cost: Cost(0),
+        };
+        let placeholder_pt = EdgePT {
+            leavetime: LeavingTime(0),
+            cost: Cost(0),
+            to: NodeID(0),
+            trip: TripID::UNKNOWN,
+        };
+        let mut walk_incoming = vec![placeholder_walk; walk_offsets[node_count] as usize];
+        let mut pt_incoming = vec![placeholder_pt; pt_offsets[node_count] as usize];
+        let mut walk_cursor = walk_offsets.clone();
+        let mut pt_cursor = pt_offsets.clone();
+        for node in 0..node_count as u32 {
+            for edge in graph.walk_edges(node).iter().skip(1) {
+                let slot = &mut walk_cursor[edge.to.0 as usize];
+                walk_incoming[*slot as usize] = EdgeWalk {
+                    to: NodeID(node),
+                    cost: edge.cost,
+                };
+                *slot += 1;
+            }
+            for edge in graph.pt_edges(node) {
+                let slot = &mut pt_cursor[edge.to.0 as usize];
+                pt_incoming[*slot as usize] = EdgePT {
+                    to: NodeID(node),
+                    ..*edge
+                };
+                *slot += 1;
+            }
+        }
+
+        for node in 0..node_count {
+            pt_incoming[pt_offsets[node] as usize..pt_offsets[node + 1] as usize]
+                .sort_by_key(|edge| edge.leavetime.0 + edge.cost.0 as u32);
+        }
+
+        ReverseGraph {
+            walk_offsets,
+            walk_incoming,
+            pt_offsets,
+            pt_incoming,
+        }
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.walk_offsets.len() - 1
+    }
+
+    /// Walk edges into a node. There's no PT flag edge
+    #[inline]
+    pub fn walk_edges_into(&self, node: u32) -> &[EdgeWalk] {
+        &self.walk_incoming
+            [self.walk_offsets[node as usize] as usize..self.walk_offsets[node as usize + 1] as usize]
+    }
+
+    /// PT departures which arrive at a stop, ordered by arrival time
+    #[inline]
+    pub fn pt_arrivals_into(&self, node: u32) -> &[EdgePT] {
+        &self.pt_incoming
+            [self.pt_offsets[node as usize] as usize..self.pt_offsets[node as usize + 1] as usize]
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::errors::ConnectivityError;
+use crate::graph_cache::GraphCache;
+use crate::graph_overlay::GraphOverlay;
+use crate::service_days::DayType;
+use crate::shared::Scenario;
+use crate::validation::{validate_scenario, validate_scenario_node_ids};
+
+/// A scenario's overlay, ready for floodfills
+pub struct PreparedScenario {
+    pub year: i32,
+    /// Including new stops on new routes
+    pub new_nodes_count: usize,
+    pub overlay: Arc<GraphOverlay>,
+}
+
+/// Scenarios by ID. Each is written to `{dir}/{id}.json` when it's created, so they outlive the
+/// server, and its overlay is built once per day type and kept. Overlays are dropped once their
+/// year has been evicted from the graph cache, so they don't keep it in memory, and rebuilt if
+/// they're asked for again
+pub struct ScenarioStore {
+    dir: PathBuf,
+    prepared: Mutex<HashMap<(String, DayType), Arc<PreparedScenario>>>,
+}
+
+impl ScenarioStore {
+    pub fn new(dir: PathBuf) -> ScenarioStore {
+        ScenarioStore {
+            dir,
+            prepared: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Validates and stores a scenario, returning its ID
+    pub fn create(
+        &self,
+        scenario: Scenario,
+        graph_cache: &GraphCache,
+    ) -> Result<String, ConnectivityError> {
+        validate_scenario(&scenario)?;
+        let year_data = graph_cache.get(scenario.year)?;
+        self.drop_evicted(graph_cache);
+        validate_scenario_node_ids(&scenario, year_data.graph_walk.len())?;
+        let day_type = DayType::default();
+        let service_day = year_data.service_day(day_type)?;
+        let overlay = GraphOverlay::from_edits(year_data, service_day, &scenario.edits)?;
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let path = self.path(&id);
+        std::fs::create_dir_all(&self.dir)
+            .and_then(|_| std::fs::write(&path, serde_json::to_string(&scenario).unwrap()))
+            .map_err(|err| ConnectivityError::DataFile {
+                path: path.display().to_string(),
+                message: err.to_string(),
+            })?;
+
+        self.prepared.lock().unwrap().insert(
+            (id.clone(), day_type),
+            Arc::new(PreparedScenario {
+                year: scenario.year,
+                new_nodes_count: scenario.edits.added_nodes_count(),
+                overlay: Arc::new(overlay),
+            }),
+        );
+        Ok(id)
+    }
+
+    /// The scenario's overlay for a day type, read from disk the first time it's asked for since
+    /// the server started
+    pub fn get(
+        &self,
+        id: &str,
+        day_type: DayType,
+        graph_cache: &GraphCache,
+    ) -> Result<Arc<PreparedScenario>, ConnectivityError> {
+        // Only IDs we could have made, so the path can't point outside the scenarios directory
+        if uuid::Uuid::parse_str(id).is_err() {
+            return Err(ConnectivityError::ScenarioNotFound(id.to_string()));
+        }
+        let key = (id.to_string(), day_type);
+        let cached = self.prepared.lock().unwrap().get(&key).cloned();
+        if let Some(prepared) = cached {
+            let year_data = graph_cache.get(prepared.year)?;
+            self.drop_evicted(graph_cache);
+            if Arc::ptr_eq(prepared.overlay.base(), &year_data) {
+                return Ok(prepared);
+            }
+            println!("Rebuilding scenario {} as {} was reloaded", id, prepared.year);
+        }
+
+        let scenario = self.read(id)?;
+        let year_data = graph_cache.get(scenario.year)?;
+        self.drop_evicted(graph_cache);
+        // Checked when it was created, but the year's network could have been reserialised since
+        validate_scenario_node_ids(&scenario, year_data.graph_walk.len())?;
+        let service_day = year_data.service_day(day_type)?;
+        let prepared = Arc::new(PreparedScenario {
+            year: scenario.year,
+            new_nodes_count: scenario.edits.added_nodes_count(),
+            overlay: Arc::new(GraphOverlay::from_edits(year_data, service_day, &scenario.edits)?),
+        });
+        self.prepared.lock().unwrap().insert(key, prepared.clone());
+        Ok(prepared)
+    }
+
+    /// Forgets overlays built on years the graph cache has since evicted. Called whenever a year
+    /// is fetched, as that's when years get evicted
+    pub fn drop_evicted(&self, graph_cache: &GraphCache) {
+        self.prepared.lock().unwrap().retain(|(id, _), prepared| {
+            let evicted = graph_cache.is_evicted(prepared.year, prepared.overlay.base());
+            if evicted {
+                println!("Dropping scenario {} as {} was evicted", id, prepared.year);
+            }
+            !evicted
+        });
+    }
+
+    fn read(&self, id: &str) -> Result<Scenario, ConnectivityError> {
+        let path = self.path(id);
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(ConnectivityError::ScenarioNotFound(id.to_string()));
+            }
+            Err(err) => {
+                return Err(ConnectivityError::DataFile {
+                    path: path.display().to_string(),
+                    message: err.to_string(),
+                })
+            }
+        };
+        serde_json::from_str(&contents).map_err(|err| ConnectivityError::DataFile {
+            path: path.display().to_string(),
+            message: err.to_string(),
+        })
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+}
@@ -3,8 +3,9 @@ use std::time::Instant;
 
 use fs_err::File;
 use std::io::BufWriter;
+use std::path::Path;
 
-use crate::shared::{Cost, EdgePT, EdgeWalk, LeavingTime, NodeID};
+use crate::shared::{pt_departures_from_legacy, Cost, EdgePT, EdgeWalk, LeavingTime, NodeID, TripID};
 
 pub fn serialise_files_all_years() {
     for year in 2016..2023 {
@@ -38,6 +39,7 @@ pub fn serialise_files(year: i32) {
     serialise_list(&padded_nodes_filename);
     let len_graph_walk = serialise_graph_walk_vector(year);
     serialise_graph_pt_vector(year, len_graph_walk);
+    serialise_trip_service_days(year);
     serialise_node_values_padding_count(year);
 
     serialise_list_immutable_array_i8("subpurpose_purpose_lookup");
@@ -81,26 +83,58 @@ fn serialise_graph_walk_vector(year: i32) -> usize {
     let filename = format!("serialised_data/p1_main_nodes_vector_6am_{}.bin", year);
     let file = BufWriter::new(File::create(filename).unwrap());
     bincode::serialize_into(file, &graph_walk_vec).unwrap();
-    return graph_walk_vec.len();
+    graph_walk_vec.len()
 }
 
+/// Reads `p2_departures_6am_{year}.json` if there is one: for each stop, a list of
+/// [leaving time, journey time, next stop ID, trip ID] departures. Otherwise migrates the legacy
+/// `p2_main_nodes_list_6am_{year}.json`, which only allows one next stop per stop
 fn serialise_graph_pt_vector(year: i32, len_graph_walk: usize) {
-    let contents_filename = format!("data/p2_main_nodes_list_6am_{}.json", year);
-    let contents = fs_err::read_to_string(contents_filename).unwrap();
-
-    let input: Vec<Vec<[usize; 2]>> = serde_json::from_str(&contents).unwrap();
-
-    let mut graph_pt_vec = Vec::new();
-    for input_edges in input.iter() {
-        let mut edges: SmallVec<[EdgePT; 4]> = SmallVec::new();
-        for array in input_edges {
-            edges.push(EdgePT {
-                leavetime: LeavingTime(array[0] as u32),
-                cost: Cost(array[1] as u16),
-            });
-        }
-        graph_pt_vec.push(edges);
-    }
+    let departures_filename = format!("data/p2_departures_6am_{}.json", year);
+
+    let mut graph_pt_vec: Vec<SmallVec<[EdgePT; 4]>> = if Path::new(&departures_filename).exists() {
+        let contents = fs_err::read_to_string(&departures_filename).unwrap();
+        let input: Vec<Vec<[usize; 4]>> = serde_json::from_str(&contents).unwrap();
+        input
+            .iter()
+            .map(|input_edges| {
+                input_edges
+                    .iter()
+                    .map(|array| EdgePT {
+                        leavetime: LeavingTime(array[0] as u32),
+                        cost: Cost(array[1] as u16),
+                        to: NodeID(array[2] as u32),
+                        trip: TripID(array[3] as u32),
+                    })
+                    .collect()
+            })
+            .collect()
+    } else {
+        let contents_filename = format!("data/p2_main_nodes_list_6am_{}.json", year);
+        let contents = fs_err::read_to_string(contents_filename).unwrap();
+        let input: Vec<Vec<[usize; 2]>> = serde_json::from_str(&contents).unwrap();
+        println!("Migrating legacy PT graph for year {}", year);
+        input
+            .iter()
+            .map(|input_edges| pt_departures_from_legacy(input_edges))
+            .collect()
+    };
+
+    // The floodfill binary searches each stop's departures, so they must be in leaving time order.
+    // Out of order departures mean something's wrong upstream, so stop rather than hide it
+    let unsorted_stops: Vec<usize> = graph_pt_vec
+        .iter()
+        .enumerate()
+        .filter(|(_, edges)| !edges.windows(2).all(|pair| pair[0].leavetime <= pair[1].leavetime))
+        .map(|(node, _)| node)
+        .collect();
+    assert!(
+        unsorted_stops.is_empty(),
+        "Departures from {} stops in year {} aren't in leaving time order, eg nodes {:?}",
+        unsorted_stops.len(),
+        year,
+        &unsorted_stops[..unsorted_stops.len().min(10)]
+    );
 
     for _ in graph_pt_vec.len()..len_graph_walk {
         let edges: SmallVec<[EdgePT; 4]> = SmallVec::new();
@@ -108,11 +142,37 @@ fn serialise_graph_pt_vector(year: i32, len_graph_walk: usize) {
     }
     assert!(graph_pt_vec.len() == len_graph_walk);
 
-    let filename = format!("serialised_data/p2_main_nodes_vector_6am_{}.bin", year);
+    // Named differently to the old single-next-stop files, so they can't be read by mistake
+    let filename = format!("serialised_data/p2_departures_vector_6am_{}.bin", year);
     let file = BufWriter::new(File::create(filename).unwrap());
     bincode::serialize_into(file, &graph_pt_vec).unwrap();
 }
 
+/// Reads `trip_service_days_6am_{year}.json` if there is one: a day mask for each trip ID, with
+/// bit 0 for Monday up to bit 6 for Sunday. Without it, every trip runs every day and requests can
+/// only ask for weekdays
+fn serialise_trip_service_days(year: i32) {
+    let inpath = format!("data/trip_service_days_6am_{}.json", year);
+    let outpath = format!("serialised_data/trip_service_days_6am_{}.bin", year);
+    if !Path::new(&inpath).exists() {
+        // Don't leave a calendar from an older timetable lying around
+        if Path::new(&outpath).exists() {
+            fs_err::remove_file(&outpath).unwrap();
+        }
+        println!("No service calendar for year {}", year);
+        return;
+    }
+    let contents = fs_err::read_to_string(&inpath).unwrap();
+    let output: Vec<u8> = serde_json::from_str(&contents).unwrap();
+    assert!(
+        output.iter().all(|days| *days < 1 << 7),
+        "Day masks only have 7 bits"
+    );
+    let file = BufWriter::new(File::create(&outpath).unwrap());
+    bincode::serialize_into(file, &output).unwrap();
+    println!("Serialised to {}", outpath);
+}
+
 fn serialise_list(filename: &str) {
     let inpath = format!("data/{}.json", filename);
     let contents = fs_err::read_to_string(&inpath).unwrap();
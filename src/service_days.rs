@@ -0,0 +1,94 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use rayon::prelude::*;
+use serde::Deserialize;
+
+use crate::raptor::Timetable;
+use crate::reverse_graph::ReverseGraph;
+use crate::shared::{GraphPT, TripID};
+
+/// Which day's timetable a request uses. A trip counts for `weekday` only if it runs on all of
+/// Monday to Friday, so a Friday-only late service needs `friday`
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DayType {
+    #[default]
+    Weekday,
+    Saturday,
+    Sunday,
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+}
+
+impl DayType {
+    /// Bit 0 is Monday and bit 6 is Sunday, the same as trip_service_days
+    pub fn mask(self) -> u8 {
+        match self {
+            DayType::Weekday => 0b0011111,
+            DayType::Monday => 1,
+            DayType::Tuesday => 1 << 1,
+            DayType::Wednesday => 1 << 2,
+            DayType::Thursday => 1 << 3,
+            DayType::Friday => 1 << 4,
+            DayType::Saturday => 1 << 5,
+            DayType::Sunday => 1 << 6,
+        }
+    }
+}
+
+/// Which days each trip runs on, as a day mask indexed by trip ID. Trips past the end, including
+/// TripID::UNKNOWN and trips added by edits, run every day
+pub struct TripServiceDays(pub Vec<u8>);
+
+impl TripServiceDays {
+    pub fn runs_on(&self, trip: TripID, day_type: DayType) -> bool {
+        match self.0.get(trip.0 as usize) {
+            Some(days) => days & day_type.mask() == day_type.mask(),
+            None => true,
+        }
+    }
+}
+
+/// A year's departures with the trips that don't run on one day type taken out, and the search
+/// structures built from them. Like the year's own, the caches are built on first use
+pub struct ServiceDayData {
+    pub graph_pt: GraphPT,
+    pub reverse_graph: Mutex<Option<Arc<ReverseGraph>>>,
+    pub timetable: Mutex<Option<Arc<Timetable>>>,
+}
+
+impl ServiceDayData {
+    pub fn new(
+        graph_pt: &GraphPT,
+        trip_service_days: &TripServiceDays,
+        day_type: DayType,
+    ) -> ServiceDayData {
+        let now = Instant::now();
+        let graph_pt: GraphPT = graph_pt
+            .par_iter()
+            .map(|edges| {
+                edges
+                    .iter()
+                    .filter(|edge| trip_service_days.runs_on(edge.trip, day_type))
+                    .copied()
+                    .collect()
+            })
+            .collect();
+        let departures_count: usize = graph_pt.iter().map(|edges| edges.len()).sum();
+        println!(
+            "Filtered departures to {} running on {:?} in {:?}",
+            departures_count,
+            day_type,
+            now.elapsed()
+        );
+        ServiceDayData {
+            graph_pt,
+            reverse_graph: Mutex::new(None),
+            timetable: Mutex::new(None),
+        }
+    }
+}
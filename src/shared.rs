@@ -1,4 +1,11 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+
+use crate::raptor::Engine;
+use crate::service_days::DayType;
+use crate::travel_time_relationships::DecayCurveExtension;
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
 pub struct NodeID(pub u32);
@@ -6,32 +13,531 @@ pub struct NodeID(pub u32);
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
 pub struct Cost(pub u16);
 
+/// Seconds past midnight at the start of the service day. Services running after midnight can
+/// be timetabled past 24:00, eg 25:30 is 91800
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
 pub struct LeavingTime(pub u32);
 
+pub const SECONDS_PER_DAY: u32 = 86400;
+
 #[derive(Serialize, Deserialize, Clone, Copy)]
 pub struct EdgeWalk {
     pub to: NodeID,
     pub cost: Cost,
 }
 
+/// Identifies one vehicle's journey, so riding through a stop can be told apart from changing
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
+pub struct TripID(pub u32);
+
+impl TripID {
+    /// For timetables which don't say which trip a departure belongs to
+    pub const UNKNOWN: TripID = TripID(u32::MAX);
+}
+
+/// One departure from a stop. A stop's departures are ordered by `leavetime`, and each goes to
+/// its own next stop, so stops served by several routes don't need splitting
 #[derive(Serialize, Deserialize, Clone, Copy)]
 pub struct EdgePT {
     pub leavetime: LeavingTime,
     pub cost: Cost,
+    pub to: NodeID,
+    pub trip: TripID,
 }
 
-#[derive(Deserialize)]
-pub struct UserInputJSON {
-    pub start_nodes_user_input: Vec<i32>,
-    pub init_travel_times_user_input: Vec<i32>,
-    pub trip_start_seconds: i32,
+/// Converts one stop from the legacy `p2_main_nodes_list` format, where every stop had a single
+/// next stop: the first pair is [next stop ID, 0] and the rest are [leaving time, journey time]
+pub fn pt_departures_from_legacy(input_edges: &[[usize; 2]]) -> SmallVec<[EdgePT; 4]> {
+    let mut edges: SmallVec<[EdgePT; 4]> = SmallVec::new();
+    if let Some((header, departures)) = input_edges.split_first() {
+        for array in departures {
+            edges.push(EdgePT {
+                leavetime: LeavingTime(array[0] as u32),
+                cost: Cost(array[1] as u16),
+                to: NodeID(header[0] as u32),
+                trip: TripID::UNKNOWN,
+            });
+        }
+    }
+    edges
+}
+
+pub type GraphWalk = Vec<SmallVec<[EdgeWalk; 4]>>;
+pub type GraphPT = Vec<SmallVec<[EdgePT; 4]>>;
+
+/// For each node, a list of [subpurpose index, value] pairs. Most nodes have no value
+pub type NodeValues2d = Vec<Vec<[i32; 2]>>;
+
+/// Start node ID, IDs of every node reached, and the travel time to each of them
+pub type FloodfillOutput = (u32, Vec<u32>, Vec<u16>);
+
+/// Number of nodes reached, start node ID, score per subpurpose, target destination IDs reached
+/// and the travel times to them
+pub type FloodfillScores = (i32, u32, [i64; 32], Vec<u32>, Vec<u16>);
+
+/// Changes to a year's network and node values, either inline in a request or stored as a
+/// scenario. Every field can be left out
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct ScenarioEdits {
+    /// Walk edges for each new node, as [cost, to]. The first is the PT flag, as in the base graph
+    #[serde(default)]
     pub graph_walk_additions: Vec<Vec<[usize; 2]>>,
+    /// Departures from each new node, in the legacy format read by pt_departures_from_legacy
+    #[serde(default)]
     pub graph_pt_additions: Vec<Vec<[usize; 2]>>,
+    #[serde(default)]
     pub new_nodes_count: usize,
+    #[serde(default)]
     pub graph_walk_updates_keys: Vec<usize>,
+    /// Extra walk edges for each of graph_walk_updates_keys, as [cost, to]
+    #[serde(default)]
     pub graph_walk_updates_additions: Vec<Vec<[usize; 2]>>,
-    pub year: i32,
+    /// [value, nearest node ID, subpurpose index]
+    #[serde(default)]
     pub new_build_additions: Vec<Vec<i32>>,
+    /// Applied in order, after new_build_additions
+    #[serde(default)]
+    pub node_value_changes: Vec<NodeValueChange>,
+    /// Walk edges to take out, as [from, to]. Edges are one way, so closing a path both ways
+    /// needs both listed
+    #[serde(default)]
+    pub graph_walk_removals: Vec<[usize; 2]>,
+    /// Nodes which can't be reached, walked through or boarded at, eg a closed station
+    #[serde(default)]
+    pub disabled_nodes: Vec<usize>,
+    #[serde(default)]
+    pub pt_departure_removals: Vec<PTDepartureRemoval>,
+    /// PT routes to add, with their timetables worked out server side
+    #[serde(default)]
+    pub new_routes: Vec<NewRoute>,
+    #[serde(default)]
+    pub pt_service_changes: Vec<PTServiceChange>,
+    #[serde(default)]
+    pub extra_departures: Vec<ExtraDeparture>,
+}
+
+/// Changes the frequency or speed of the departures from a set of stops, eg every stop on a route
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PTServiceChange {
+    pub stops: Vec<usize>,
+    /// Only departures leaving from this time, in seconds past midnight. From midnight if missing
+    #[serde(default)]
+    pub start_seconds: Option<u32>,
+    /// Only departures leaving before this time. Until the end of the day if missing
+    #[serde(default)]
+    pub end_seconds: Option<u32>,
+    /// 2 doubles the number of departures from each stop to each next stop, halving headways; 0.5
+    /// halves it. Departures are respaced evenly between the first and last
+    #[serde(default)]
+    pub frequency_multiplier: Option<f64>,
+    /// Multiplies in-vehicle times, so 0.9 speeds services up by 10%. The same vehicle's later
+    /// departures from the stops are brought forward or put back to match
+    #[serde(default)]
+    pub run_time_multiplier: Option<f64>,
+}
+
+/// One more departure from an existing stop. Extra departures which leave a stop the moment
+/// another arrives there are taken to be the same vehicle
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ExtraDeparture {
+    pub stop: usize,
+    pub next_stop: usize,
+    pub leave_seconds: u32,
+    /// Taken from the existing departure to next_stop which leaves closest in time if missing
+    #[serde(default)]
+    pub run_time_seconds: Option<u16>,
+}
+
+/// A PT route, as a stop sequence and either a headway or the departure times from its first
+/// stop
+#[derive(Deserialize, Serialize, Clone)]
+pub struct NewRoute {
+    pub stops: Vec<RouteStop>,
+    /// Seconds from each stop to the next, so one fewer than `stops`
+    pub run_times_seconds: Vec<u16>,
+    /// Seconds past midnight. With `last_departure_seconds` and `headway_seconds`, the first
+    /// stop's departures
+    #[serde(default)]
+    pub first_departure_seconds: Option<u32>,
+    /// Inclusive, if it falls on the headway
+    #[serde(default)]
+    pub last_departure_seconds: Option<u32>,
+    #[serde(default)]
+    pub headway_seconds: Option<u32>,
+    /// Departure times from the first stop, instead of a headway
+    #[serde(default)]
+    pub departure_times_seconds: Vec<u32>,
+}
+
+/// A stop on a new route: either an existing node ID, or a new stop linked to the walk network
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum RouteStop {
+    Existing(usize),
+    New {
+        /// Walk links as [cost, node], which are added both ways
+        walk_links: Vec<[usize; 2]>,
+    },
+}
+
+impl NewRoute {
+    /// Departure times from the first stop, in order
+    pub fn departure_times(&self) -> Vec<u32> {
+        let mut times = match (
+            self.first_departure_seconds,
+            self.last_departure_seconds,
+            self.headway_seconds,
+        ) {
+            (Some(first), Some(last), Some(headway)) if headway > 0 => {
+                (first..=last).step_by(headway as usize).collect()
+            }
+            _ => self.departure_times_seconds.clone(),
+        };
+        times.sort_unstable();
+        times
+    }
+}
+
+/// A change to the destination values at a node, eg `{"operation": "scale", "node": 123,
+/// "subpurpose": 4, "multiplier": 0.5}`. Without a subpurpose, every subpurpose at the node changes
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum NodeValueChange {
+    /// Replaces the node's value for the subpurpose
+    Set {
+        node: usize,
+        subpurpose: u8,
+        value: i32,
+    },
+    Scale {
+        node: usize,
+        #[serde(default)]
+        subpurpose: Option<u8>,
+        multiplier: f64,
+    },
+    Remove {
+        node: usize,
+        #[serde(default)]
+        subpurpose: Option<u8>,
+    },
+    /// Moves the values to `to_node`, adding them to any it already has
+    Relocate {
+        node: usize,
+        to_node: usize,
+        #[serde(default)]
+        subpurpose: Option<u8>,
+    },
+}
+
+impl NodeValueChange {
+    pub fn node(&self) -> usize {
+        match self {
+            NodeValueChange::Set { node, .. }
+            | NodeValueChange::Scale { node, .. }
+            | NodeValueChange::Remove { node, .. }
+            | NodeValueChange::Relocate { node, .. } => *node,
+        }
+    }
+
+    /// None for every subpurpose
+    pub fn subpurpose(&self) -> Option<u8> {
+        match self {
+            NodeValueChange::Set { subpurpose, .. } => Some(*subpurpose),
+            NodeValueChange::Scale { subpurpose, .. }
+            | NodeValueChange::Remove { subpurpose, .. }
+            | NodeValueChange::Relocate { subpurpose, .. } => *subpurpose,
+        }
+    }
+}
+
+/// Takes out departures from a stop which leave in a time range, eg a withdrawn service
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PTDepartureRemoval {
+    pub stop: usize,
+    /// Seconds past midnight, inclusive
+    pub start_seconds: u32,
+    /// Seconds past midnight, exclusive
+    pub end_seconds: u32,
+    /// Only take out departures to this stop. All of them if missing
+    #[serde(default)]
+    pub next_stop: Option<usize>,
+}
+
+impl ScenarioEdits {
+    pub fn is_empty(&self) -> bool {
+        self.graph_walk_additions.is_empty()
+            && self.graph_pt_additions.is_empty()
+            && self.new_nodes_count == 0
+            && self.graph_walk_updates_keys.is_empty()
+            && self.graph_walk_updates_additions.is_empty()
+            && self.new_build_additions.is_empty()
+            && self.node_value_changes.is_empty()
+            && self.graph_walk_removals.is_empty()
+            && self.disabled_nodes.is_empty()
+            && self.pt_departure_removals.is_empty()
+            && self.new_routes.is_empty()
+            && self.pt_service_changes.is_empty()
+            && self.extra_departures.is_empty()
+    }
+
+    /// New nodes from graph_walk_additions, then each new stop on the new routes in order
+    pub fn added_nodes_count(&self) -> usize {
+        let new_stops_count = self
+            .new_routes
+            .iter()
+            .flat_map(|route| &route.stops)
+            .filter(|stop| matches!(stop, RouteStop::New { .. }))
+            .count();
+        self.new_nodes_count + new_stops_count
+    }
+}
+
+/// A base year and edits to it, stored by POST /scenarios/ so requests can refer to it by ID
+#[derive(Deserialize, Serialize)]
+pub struct Scenario {
+    pub year: i32,
+    #[serde(flatten)]
+    pub edits: ScenarioEdits,
+}
+
+#[derive(Deserialize)]
+pub struct UserInputJSON {
+    pub start_nodes_user_input: Vec<i32>,
+    pub init_travel_times_user_input: Vec<i32>,
+    pub trip_start_seconds: i32,
+    pub year: i32,
+    /// Only departures on trips running on this day are used. Years without a service calendar
+    /// only have a weekday timetable
+    #[serde(default)]
+    pub day_type: DayType,
+    #[serde(flatten)]
+    pub edits: ScenarioEdits,
+    /// A stored scenario to use instead of inline edits. Its year must match `year`
+    #[serde(default)]
+    pub scenario_id: Option<String>,
     pub target_destinations: Vec<u32>,
+    #[serde(default = "default_max_travel_time_seconds")]
+    pub max_travel_time_seconds: u16,
+    #[serde(default)]
+    pub decay_curve_extension: DecayCurveExtension,
+    /// Search backwards from the start nodes, treating them as destinations and
+    /// `trip_start_seconds` as the time to arrive by
+    #[serde(default)]
+    pub arrive_by: bool,
+    #[serde(default)]
+    pub profile: Option<ProfileInput>,
+    /// Most PT vehicles a journey can board. No limit if missing
+    #[serde(default)]
+    pub max_boardings: Option<u8>,
+    /// Added to the travel time every time a PT vehicle is boarded, to stand in for the hassle
+    /// of changing. Travel times and scores are worked out from the penalised times
+    #[serde(default)]
+    pub boarding_penalty_seconds: u16,
+    #[serde(default)]
+    pub engine: Engine,
+    /// Respond with the unversioned list of results, as before FloodfillResponse: FloodfillScores
+    /// tuples, or bare lists of profile or arrive-by results
+    #[serde(default)]
+    pub legacy_output: bool,
+    /// Respond with one JSON line per start node, sent as each finishes
+    #[serde(default)]
+    pub stream: bool,
+}
+
+/// Request to /compare/: a normal floodfill request, run once on the year's own network and once
+/// per scenario. The request's own scenario_id or inline edits, if any, are compared first, then
+/// each of `scenario_ids`
+#[derive(Deserialize)]
+pub struct CompareInputJSON {
+    #[serde(flatten)]
+    pub request: UserInputJSON,
+    #[serde(default)]
+    pub scenario_ids: Vec<String>,
+}
+
+/// Runs a floodfill for every departure time from `trip_start_seconds` to `window_end_seconds`
+/// inclusive, `step_seconds` apart, and summarises the results
+#[derive(Deserialize)]
+pub struct ProfileInput {
+    pub window_end_seconds: i32,
+    #[serde(default = "default_profile_step_seconds")]
+    pub step_seconds: i32,
+    /// Extra percentiles to report, on top of the min, median and max
+    #[serde(default)]
+    pub percentiles: Vec<u8>,
+}
+
+fn default_profile_step_seconds() -> i32 {
+    60
+}
+
+fn default_max_travel_time_seconds() -> u16 {
+    3600
+}
+
+/// Response to an arrive-by request, for one destination
+#[derive(Serialize)]
+pub struct ArriveByResult {
+    pub destination: u32,
+    pub origins: Vec<u32>,
+    /// Latest time each origin can be left, in seconds past midnight
+    pub latest_departure_seconds: Vec<u32>,
+}
+
+/// Bumped whenever a field of FloodfillResponse or any of its results changes
+pub const FLOODFILL_RESPONSE_VERSION: u32 = 3;
+
+/// Which kind of /floodfill_pt/ request a response answers, as each has its own results
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum FloodfillMode {
+    /// FloodfillResult per start node
+    Scores,
+    /// ProfileResult per start node
+    Profile,
+    /// ArriveByResult per destination
+    ArriveBy,
+}
+
+/// Response to a /floodfill_pt/ request. Version 1 was the bare list of FloodfillScores tuples,
+/// and version 2 only wrapped scores, without `mode`
+#[derive(Serialize)]
+pub struct FloodfillResponse<T> {
+    pub version: u32,
+    pub mode: FloodfillMode,
+    /// In the same order as the start nodes in the request
+    pub results: Vec<T>,
+}
+
+impl<T: Serialize> FloodfillResponse<T> {
+    /// The versioned response, or just the results for `legacy_output`
+    pub fn to_json(mode: FloodfillMode, results: Vec<T>, legacy_output: bool) -> String {
+        if legacy_output {
+            return serde_json::to_string(&results).unwrap();
+        }
+        serde_json::to_string(&FloodfillResponse {
+            version: FLOODFILL_RESPONSE_VERSION,
+            mode,
+            results,
+        })
+        .unwrap()
+    }
+}
+
+/// Scores and target destination travel times for one start node
+#[derive(Serialize)]
+pub struct FloodfillResult {
+    pub start_node: u32,
+    pub nodes_reached: i32,
+    /// Keyed by subpurpose index, for all 32 subpurposes
+    pub scores_by_subpurpose: BTreeMap<u8, i64>,
+    /// Subpurpose scores summed by purpose index, using the subpurpose to purpose lookup
+    pub scores_by_purpose: BTreeMap<u8, i64>,
+    /// Keyed by target destination. Those not reached within the time limit are left out
+    pub target_destination_travel_times: BTreeMap<u32, u16>,
+}
+
+impl FloodfillResult {
+    pub fn from_scores(scores: &FloodfillScores, subpurpose_purpose_lookup: &[i8; 32]) -> Self {
+        let (nodes_reached, start_node, subpurpose_scores, target_destinations, travel_times) =
+            scores;
+        let mut scores_by_purpose = BTreeMap::new();
+        for (subpurpose_ix, score) in subpurpose_scores.iter().enumerate() {
+            let purpose_ix = subpurpose_purpose_lookup[subpurpose_ix];
+            if purpose_ix >= 0 {
+                *scores_by_purpose.entry(purpose_ix as u8).or_insert(0) += score;
+            }
+        }
+        FloodfillResult {
+            start_node: *start_node,
+            nodes_reached: *nodes_reached,
+            scores_by_subpurpose: (0..32).zip(subpurpose_scores.iter().copied()).collect(),
+            scores_by_purpose,
+            target_destination_travel_times: target_destinations
+                .iter()
+                .copied()
+                .zip(travel_times.iter().copied())
+                .collect(),
+        }
+    }
+}
+
+/// Bumped whenever a field of CompareResponse or anything in it changes
+pub const COMPARE_RESPONSE_VERSION: u32 = 1;
+
+/// Response to a /compare/ request
+#[derive(Serialize)]
+pub struct CompareResponse {
+    pub version: u32,
+    pub year: i32,
+    /// In the order the scenarios were given
+    pub comparisons: Vec<ScenarioComparison>,
+}
+
+#[derive(Serialize)]
+pub struct ScenarioComparison {
+    /// Missing for the request's inline edits
+    pub scenario_id: Option<String>,
+    /// In the same order as the start nodes in the request
+    pub results: Vec<ComparisonResult>,
+}
+
+/// How one start node's results change under a scenario. Every change is scenario minus base
+#[derive(Serialize)]
+pub struct ComparisonResult {
+    pub start_node: u32,
+    pub nodes_reached_change: i32,
+    pub score_changes_by_subpurpose: BTreeMap<u8, i64>,
+    pub score_changes_by_purpose: BTreeMap<u8, i64>,
+    /// Target destinations newly reached, no longer reached, or reached in a different time.
+    /// Ordered by destination
+    pub changed_destinations: Vec<DestinationChange>,
+}
+
+#[derive(Serialize)]
+pub struct DestinationChange {
+    pub destination: u32,
+    /// Missing where the destination isn't reached within the time limit
+    pub base_travel_time: Option<u16>,
+    pub scenario_travel_time: Option<u16>,
+}
+
+impl ComparisonResult {
+    pub fn new(base: &FloodfillResult, scenario: &FloodfillResult) -> Self {
+        let score_changes = |base: &BTreeMap<u8, i64>, scenario: &BTreeMap<u8, i64>| {
+            scenario
+                .iter()
+                .map(|(ix, score)| (*ix, score - base.get(ix).copied().unwrap_or(0)))
+                .collect()
+        };
+        let base_times = &base.target_destination_travel_times;
+        let scenario_times = &scenario.target_destination_travel_times;
+        let mut destinations: Vec<u32> =
+            base_times.keys().chain(scenario_times.keys()).copied().collect();
+        destinations.sort_unstable();
+        destinations.dedup();
+        ComparisonResult {
+            start_node: scenario.start_node,
+            nodes_reached_change: scenario.nodes_reached - base.nodes_reached,
+            score_changes_by_subpurpose: score_changes(
+                &base.scores_by_subpurpose,
+                &scenario.scores_by_subpurpose,
+            ),
+            score_changes_by_purpose: score_changes(
+                &base.scores_by_purpose,
+                &scenario.scores_by_purpose,
+            ),
+            changed_destinations: destinations
+                .into_iter()
+                .filter(|destination| {
+                    base_times.get(destination) != scenario_times.get(destination)
+                })
+                .map(|destination| DestinationChange {
+                    destination,
+                    base_travel_time: base_times.get(&destination).copied(),
+                    scenario_travel_time: scenario_times.get(&destination).copied(),
+                })
+                .collect(),
+        }
+    }
 }
@@ -0,0 +1,214 @@
+use std::borrow::Cow;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::errors::ConnectivityError;
+use crate::travel_time_relationships::{DecayCurveExtension, TravelTimeRelationships};
+
+/// Which decay curves to use for which departure times. Read from a JSON file at startup, eg
+/// `{"interpolation_window_seconds": 1800, "bands": [{"name": "am_peak", "start_seconds": 0,
+/// "travel_time_relationships_file": "travel_time_relationships_7"}, ...]}`
+#[derive(Deserialize)]
+pub struct TimeBandsManifest {
+    /// Departures within half this of a band boundary get a blend of the curves either side,
+    /// weighted by how close they are. 0 switches straight from one band to the next
+    #[serde(default)]
+    pub interpolation_window_seconds: u32,
+    /// In order of start time. The first has to start at midnight
+    pub bands: Vec<TimeBandEntry>,
+}
+
+#[derive(Deserialize)]
+pub struct TimeBandEntry {
+    pub name: String,
+    /// Seconds past midnight, inclusive
+    pub start_seconds: u32,
+    /// Name of a bincoded file in serialised_data, without the .bin
+    pub travel_time_relationships_file: String,
+}
+
+impl TimeBandsManifest {
+    /// The four bands the API has always used, if there's no manifest
+    pub fn read(path: &str) -> Result<TimeBandsManifest, ConnectivityError> {
+        if !Path::new(path).exists() {
+            println!("No time bands manifest at {}, so using the default 4 bands", path);
+            return Ok(TimeBandsManifest::default_bands());
+        }
+        let contents = std::fs::read_to_string(path).map_err(|err| ConnectivityError::DataFile {
+            path: path.to_string(),
+            message: err.to_string(),
+        })?;
+        serde_json::from_str(&contents).map_err(|err| ConnectivityError::DataFile {
+            path: path.to_string(),
+            message: err.to_string(),
+        })
+    }
+
+    fn default_bands() -> TimeBandsManifest {
+        // Boundaries are exclusive of the hour, eg 10:00:00 exactly is still the morning
+        let band = |name: &str, start_seconds: u32, hour: u32| TimeBandEntry {
+            name: name.to_string(),
+            start_seconds,
+            travel_time_relationships_file: format!("travel_time_relationships_{}", hour),
+        };
+        TimeBandsManifest {
+            interpolation_window_seconds: 0,
+            bands: vec![
+                band("7", 0, 7),
+                band("10", 3600 * 10 + 1, 10),
+                band("16", 3600 * 16 + 1, 16),
+                band("19", 3600 * 19 + 1, 19),
+            ],
+        }
+    }
+
+    pub fn travel_time_relationships_files(&self) -> Vec<String> {
+        self.bands
+            .iter()
+            .map(|band| band.travel_time_relationships_file.clone())
+            .collect()
+    }
+}
+
+/// The time bands from the manifest, with their decay curves loaded
+pub struct TimeBands {
+    names: Vec<String>,
+    start_seconds: Vec<u32>,
+    travel_time_relationships: Vec<TravelTimeRelationships>,
+    interpolation_window_seconds: u32,
+}
+
+impl TimeBands {
+    /// Takes the curves for each band in the manifest's order
+    pub fn new(
+        manifest: TimeBandsManifest,
+        travel_time_relationships: Vec<TravelTimeRelationships>,
+        manifest_path: &str,
+    ) -> Result<TimeBands, ConnectivityError> {
+        let invalid = |message: String| ConnectivityError::DataFile {
+            path: manifest_path.to_string(),
+            message,
+        };
+        if manifest.bands.is_empty() {
+            return Err(invalid("needs at least 1 band".to_string()));
+        }
+        if manifest.bands[0].start_seconds != 0 {
+            return Err(invalid("the first band has to start at 0".to_string()));
+        }
+        for pair in manifest.bands.windows(2) {
+            if pair[1].start_seconds <= pair[0].start_seconds {
+                return Err(invalid(format!(
+                    "band {} has to start after band {}",
+                    pair[1].name, pair[0].name
+                )));
+            }
+        }
+        // Blending needs the curves to line up
+        if manifest.interpolation_window_seconds > 0 {
+            for (band, curves) in manifest.bands.iter().zip(&travel_time_relationships) {
+                if curves.values.len() != travel_time_relationships[0].values.len() {
+                    return Err(invalid(format!(
+                        "band {}'s curves are a different length to band {}'s, so they can't \
+                         be interpolated",
+                        band.name, manifest.bands[0].name
+                    )));
+                }
+            }
+        }
+        Ok(TimeBands {
+            names: manifest.bands.iter().map(|band| band.name.clone()).collect(),
+            start_seconds: manifest.bands.iter().map(|band| band.start_seconds).collect(),
+            travel_time_relationships,
+            interpolation_window_seconds: manifest.interpolation_window_seconds,
+        })
+    }
+
+    pub fn band_index(&self, trip_start_seconds: i32) -> usize {
+        let seconds = trip_start_seconds.max(0) as u32;
+        self.start_seconds.partition_point(|start| *start <= seconds) - 1
+    }
+
+    pub fn band_name(&self, trip_start_seconds: i32) -> &str {
+        &self.names[self.band_index(trip_start_seconds)]
+    }
+
+    /// The band's curves, or a blend of two bands' curves near the boundary between them
+    pub fn travel_time_relationships(
+        &self,
+        trip_start_seconds: i32,
+    ) -> Cow<'_, TravelTimeRelationships> {
+        self.travel_time_relationships_among(trip_start_seconds, &self.travel_time_relationships)
+    }
+
+    /// Every band's curves extended to cover `max_travel_time_seconds`, or None if they already
+    /// do. Extending is slow, so requests with many departure times do it once up front
+    pub fn extended_to(
+        &self,
+        max_travel_time_seconds: u16,
+        extension: DecayCurveExtension,
+    ) -> Option<Vec<TravelTimeRelationships>> {
+        let extended: Vec<Option<TravelTimeRelationships>> = self
+            .travel_time_relationships
+            .iter()
+            .map(|curves| curves.extended_to(max_travel_time_seconds, extension))
+            .collect();
+        if extended.iter().all(|curves| curves.is_none()) {
+            return None;
+        }
+        Some(
+            extended
+                .into_iter()
+                .zip(&self.travel_time_relationships)
+                .map(|(extended, curves)| extended.unwrap_or_else(|| curves.clone()))
+                .collect(),
+        )
+    }
+
+    /// As travel_time_relationships, but choosing between and blending `curves`, which has an
+    /// entry for each band, eg from extended_to
+    pub fn travel_time_relationships_among<'a>(
+        &self,
+        trip_start_seconds: i32,
+        curves: &'a [TravelTimeRelationships],
+    ) -> Cow<'a, TravelTimeRelationships> {
+        let band_ix = self.band_index(trip_start_seconds);
+        let half_window = self.interpolation_window_seconds as i64 / 2;
+        if half_window > 0 {
+            let seconds = trip_start_seconds as i64;
+            // The nearest boundary is either this band's start or the next band's
+            for next_ix in [band_ix, band_ix + 1] {
+                if next_ix == 0 || next_ix >= self.start_seconds.len() {
+                    continue;
+                }
+                let boundary = self.start_seconds[next_ix] as i64;
+                if (seconds - boundary).abs() < half_window {
+                    let fraction = (seconds - (boundary - half_window)) as f64
+                        / self.interpolation_window_seconds as f64;
+                    return Cow::Owned(blend(&curves[next_ix - 1], &curves[next_ix], fraction));
+                }
+            }
+        }
+        Cow::Borrowed(&curves[band_ix])
+    }
+}
+
+/// `fraction` of the way from `before` to `after`
+fn blend(
+    before: &TravelTimeRelationships,
+    after: &TravelTimeRelationships,
+    fraction: f64,
+) -> TravelTimeRelationships {
+    let values = before
+        .values
+        .iter()
+        .zip(&after.values)
+        .map(|(before, after)| {
+            (*before as f64 * (1.0 - fraction) + *after as f64 * fraction).round() as i32
+        })
+        .collect();
+    TravelTimeRelationships {
+        values,
+        stride: before.stride,
+    }
+}
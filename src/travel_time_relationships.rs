@@ -0,0 +1,93 @@
+use serde::Deserialize;
+
+/// How to weight travel times beyond the end of the decay curves, which only go up to an hour
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DecayCurveExtension {
+    /// Carry on down the slope of the last 10 minutes of each curve until it reaches zero. Curves
+    /// which flatten out at the end still reach zero an hour past it, rather than staying flat
+    #[default]
+    Extend,
+    /// Resample each curve so it spans the new cutoff: the weight at 120 minutes with a 120
+    /// minute cutoff is what the original curve gives at 60 minutes
+    Stretch,
+}
+
+/// Decay curves for every purpose, stored end to end. The multiplier for purpose `p` at `t`
+/// seconds is `values[p * stride + t]`, so `stride - 1` is the longest travel time covered
+#[derive(Clone)]
+pub struct TravelTimeRelationships {
+    pub values: Vec<i32>,
+    pub stride: usize,
+}
+
+impl TravelTimeRelationships {
+    pub fn new(values: Vec<i32>, purposes_count: usize) -> TravelTimeRelationships {
+        assert_eq!(values.len() % purposes_count, 0);
+        let stride = values.len() / purposes_count;
+        TravelTimeRelationships { values, stride }
+    }
+
+    pub fn purposes_count(&self) -> usize {
+        self.values.len() / self.stride
+    }
+
+    pub fn max_travel_time_seconds(&self) -> usize {
+        self.stride - 1
+    }
+
+    #[inline]
+    pub fn multiplier(&self, purpose_ix: usize, travel_time: u16) -> i32 {
+        self.values[purpose_ix * self.stride + travel_time as usize]
+    }
+
+    /// Curves covering travel times up to `max_travel_time_seconds`. Returns None when these
+    /// curves already go far enough
+    pub fn extended_to(
+        &self,
+        max_travel_time_seconds: u16,
+        extension: DecayCurveExtension,
+    ) -> Option<TravelTimeRelationships> {
+        if max_travel_time_seconds as usize <= self.max_travel_time_seconds() {
+            return None;
+        }
+        let new_stride = max_travel_time_seconds as usize + 1;
+
+        let mut values = Vec::with_capacity(new_stride * self.purposes_count());
+        for purpose_ix in 0..self.purposes_count() {
+            let curve = &self.values[purpose_ix * self.stride..(purpose_ix + 1) * self.stride];
+            match extension {
+                DecayCurveExtension::Extend => {
+                    values.extend_from_slice(curve);
+                    let tail_seconds = 600.min(curve.len() - 1);
+                    let last = curve[curve.len() - 1] as f64;
+                    // At least steep enough to reach zero within the curve's own length again
+                    let min_decay = last / (curve.len() - 1).max(1) as f64;
+                    let slope = ((last - curve[curve.len() - 1 - tail_seconds] as f64)
+                        / tail_seconds as f64)
+                        .min(-min_decay);
+                    for seconds_past_end in 1..=(new_stride - curve.len()) {
+                        let extrapolated = last + slope * seconds_past_end as f64;
+                        values.push(extrapolated.round().max(0.0) as i32);
+                    }
+                }
+                DecayCurveExtension::Stretch => {
+                    let scale = (curve.len() - 1) as f64 / (new_stride - 1) as f64;
+                    for seconds in 0..new_stride {
+                        let position = seconds as f64 * scale;
+                        let below = position.floor() as usize;
+                        let above = (below + 1).min(curve.len() - 1);
+                        let fraction = position - below as f64;
+                        let interpolated = curve[below] as f64 * (1.0 - fraction)
+                            + curve[above] as f64 * fraction;
+                        values.push(interpolated.round() as i32);
+                    }
+                }
+            }
+        }
+        Some(TravelTimeRelationships {
+            values,
+            stride: new_stride,
+        })
+    }
+}
@@ -0,0 +1,642 @@
+use crate::errors::{ConnectivityError, FieldError};
+use crate::raptor::Engine;
+use crate::shared::{
+    CompareInputJSON, NewRoute, NodeValueChange, PTServiceChange, RouteStop, Scenario,
+    ScenarioEdits, UserInputJSON,
+};
+
+/// Every departure time in a profile window is a floodfill from each start node, so a whole day at
+/// one second steps would tie up the server for hours. Timetables are to the minute, so steps any
+/// smaller than that over a whole day don't change the percentiles
+const MAX_PROFILE_DEPARTURES: i64 = 1440;
+
+/// Each departure on a new route is a trip added to the graph and kept for as long as the
+/// request or stored scenario, so a long day with a tiny headway could run the server out of
+/// memory. 1440 is more trips a day than even the busiest metro lines run
+const MAX_NEW_ROUTE_DEPARTURES: u64 = 1440;
+
+/// Checks everything about a request which doesn't need the year's network. Lists every problem
+/// found, with the path of the field at fault
+pub fn validate_user_input(input: &UserInputJSON) -> Result<(), ConnectivityError> {
+    let mut problems: Vec<FieldError> = vec![];
+    check_user_input(input, &mut problems);
+    into_result(problems)
+}
+
+/// Checks a /route_pt/ request, as validate_user_input does. Routes are found with the default
+/// engine, from one departure time, without a boarding limit or penalty
+pub fn validate_route_input(input: &UserInputJSON) -> Result<(), ConnectivityError> {
+    let mut problems: Vec<FieldError> = vec![];
+    check_user_input(input, &mut problems);
+    for (field, unsupported) in [
+        ("arrive_by", input.arrive_by),
+        ("profile", input.profile.is_some()),
+        ("stream", input.stream),
+        ("max_boardings", input.max_boardings.is_some()),
+        ("boarding_penalty_seconds", input.boarding_penalty_seconds > 0),
+        ("engine", input.engine != Engine::Dijkstra),
+    ] {
+        if unsupported {
+            problems.push(FieldError::new(field, "isn't supported by /route_pt/"));
+        }
+    }
+    into_result(problems)
+}
+
+/// Checks a /compare/ request, as validate_user_input does
+pub fn validate_compare_input(input: &CompareInputJSON) -> Result<(), ConnectivityError> {
+    let mut problems: Vec<FieldError> = vec![];
+    let request = &input.request;
+    check_user_input(request, &mut problems);
+    for (field, unsupported) in [
+        ("arrive_by", request.arrive_by),
+        ("profile", request.profile.is_some()),
+        ("stream", request.stream),
+        ("legacy_output", request.legacy_output),
+    ] {
+        if unsupported {
+            problems.push(FieldError::new(field, "isn't supported when comparing scenarios"));
+        }
+    }
+    if !request.edits.is_empty() && !input.scenario_ids.is_empty() {
+        problems.push(FieldError::new(
+            "scenario_ids",
+            "can't be combined with inline edits: store them as a scenario too",
+        ));
+    }
+    if request.edits.is_empty() && request.scenario_id.is_none() && input.scenario_ids.is_empty()
+    {
+        problems.push(FieldError::new(
+            "scenario_ids",
+            "nothing to compare: give scenario_id, scenario_ids or inline edits",
+        ));
+    }
+    for (ix, scenario_id) in input.scenario_ids.iter().enumerate() {
+        if request.scenario_id.as_ref() == Some(scenario_id) {
+            problems.push(FieldError::new(
+                format!("scenario_ids[{}]", ix),
+                "is already given as scenario_id",
+            ));
+        }
+    }
+    into_result(problems)
+}
+
+/// Start nodes for /compare/ have to be in the year's own network, as well as every scenario's
+pub fn validate_base_start_nodes(
+    input: &UserInputJSON,
+    base_node_count: usize,
+) -> Result<(), ConnectivityError> {
+    let mut problems: Vec<FieldError> = vec![];
+    for (ix, node) in input.start_nodes_user_input.iter().enumerate() {
+        // Negative ones are caught by validate_compare_input
+        if *node >= 0 && *node as usize >= base_node_count {
+            problems.push(FieldError::new(
+                format!("start_nodes_user_input[{}]", ix),
+                format!(
+                    "node {} isn't in {}'s own network, so there's nothing to compare it to",
+                    node, input.year
+                ),
+            ));
+        }
+    }
+    into_result(problems)
+}
+
+fn check_user_input(input: &UserInputJSON, problems: &mut Vec<FieldError>) {
+
+    if input.init_travel_times_user_input.len() != input.start_nodes_user_input.len() {
+        problems.push(FieldError::new(
+            "init_travel_times_user_input",
+            format!(
+                "has {} entries but start_nodes_user_input has {}",
+                input.init_travel_times_user_input.len(),
+                input.start_nodes_user_input.len()
+            ),
+        ));
+    }
+    for (ix, node) in input.start_nodes_user_input.iter().enumerate() {
+        if *node < 0 {
+            problems.push(FieldError::new(
+                format!("start_nodes_user_input[{}]", ix),
+                "node IDs can't be negative",
+            ));
+        }
+    }
+    for (ix, travel_time) in input.init_travel_times_user_input.iter().enumerate() {
+        if *travel_time < 0 || *travel_time > u16::MAX as i32 {
+            problems.push(FieldError::new(
+                format!("init_travel_times_user_input[{}]", ix),
+                format!("must be between 0 and {} seconds", u16::MAX),
+            ));
+        }
+    }
+    if input.trip_start_seconds < 0 {
+        problems.push(FieldError::new("trip_start_seconds", "can't be negative"));
+    }
+
+    validate_edits(&input.edits, input.year, problems);
+    if input.scenario_id.is_some() && !input.edits.is_empty() {
+        problems.push(FieldError::new(
+            "scenario_id",
+            "can't be combined with inline edits: put them all in the scenario",
+        ));
+    }
+
+    if input.arrive_by && input.profile.is_some() {
+        problems.push(FieldError::new(
+            "profile",
+            "can't be combined with arrive_by",
+        ));
+    }
+    if let Some(profile) = &input.profile {
+        if profile.step_seconds <= 0 {
+            problems.push(FieldError::new("profile.step_seconds", "must be positive"));
+        }
+        if profile.window_end_seconds < input.trip_start_seconds {
+            problems.push(FieldError::new(
+                "profile.window_end_seconds",
+                "can't be before trip_start_seconds",
+            ));
+        } else if profile.step_seconds > 0 {
+            let departures = (profile.window_end_seconds as i64 - input.trip_start_seconds as i64)
+                / profile.step_seconds as i64
+                + 1;
+            if departures > MAX_PROFILE_DEPARTURES {
+                problems.push(FieldError::new(
+                    "profile",
+                    format!(
+                        "gives {} departure times, at most {} are allowed: shorten the window or \
+                         take bigger steps",
+                        departures, MAX_PROFILE_DEPARTURES
+                    ),
+                ));
+            }
+        }
+        for (ix, percentile) in profile.percentiles.iter().enumerate() {
+            if *percentile > 100 {
+                problems.push(FieldError::new(
+                    format!("profile.percentiles[{}]", ix),
+                    "must be between 0 and 100",
+                ));
+            }
+        }
+    }
+    if input.stream && (input.arrive_by || input.profile.is_some()) {
+        problems.push(FieldError::new(
+            "stream",
+            "can't be combined with arrive_by or profile",
+        ));
+    }
+    // RAPTOR only counts boardings, it doesn't weigh them
+    if input.engine == Engine::Raptor && input.boarding_penalty_seconds > 0 {
+        problems.push(FieldError::new(
+            "boarding_penalty_seconds",
+            "isn't supported by the raptor engine",
+        ));
+    }
+
+}
+
+/// Checks a stored scenario, without the year's network
+pub fn validate_scenario(scenario: &Scenario) -> Result<(), ConnectivityError> {
+    let mut problems: Vec<FieldError> = vec![];
+    validate_edits(&scenario.edits, scenario.year, &mut problems);
+    into_result(problems)
+}
+
+/// Checks every node ID in a request exists, given the number of nodes in the year's network.
+/// Nodes added by the request or its scenario count
+pub fn validate_node_ids(
+    input: &UserInputJSON,
+    base_node_count: usize,
+    scenario_new_nodes_count: usize,
+) -> Result<(), ConnectivityError> {
+    let node_count =
+        base_node_count + input.edits.added_nodes_count() + scenario_new_nodes_count;
+    let mut problems: Vec<FieldError> = vec![];
+    for (ix, node) in input.start_nodes_user_input.iter().enumerate() {
+        check_node_id(
+            format!("start_nodes_user_input[{}]", ix),
+            *node as i64,
+            node_count,
+            input.year,
+            &mut problems,
+        );
+    }
+    for (ix, node) in input.target_destinations.iter().enumerate() {
+        check_node_id(
+            format!("target_destinations[{}]", ix),
+            *node as i64,
+            node_count,
+            input.year,
+            &mut problems,
+        );
+    }
+    validate_edit_node_ids(&input.edits, node_count, input.year, &mut problems);
+    into_result(problems)
+}
+
+/// Checks every node ID in a scenario's edits exists in its year's network
+pub fn validate_scenario_node_ids(
+    scenario: &Scenario,
+    base_node_count: usize,
+) -> Result<(), ConnectivityError> {
+    let mut problems: Vec<FieldError> = vec![];
+    validate_edit_node_ids(
+        &scenario.edits,
+        base_node_count + scenario.edits.added_nodes_count(),
+        scenario.year,
+        &mut problems,
+    );
+    into_result(problems)
+}
+
+fn validate_edit_node_ids(
+    edits: &ScenarioEdits,
+    node_count: usize,
+    year: i32,
+    problems: &mut Vec<FieldError>,
+) {
+    let mut check =
+        |field: String, node: i64| check_node_id(field, node, node_count, year, problems);
+    for (ix, edges) in edits.graph_walk_additions.iter().enumerate() {
+        for (edge_ix, [_, to]) in edges.iter().enumerate() {
+            check(format!("graph_walk_additions[{}][{}][1]", ix, edge_ix), *to as i64);
+        }
+    }
+    for (ix, departures) in edits.graph_pt_additions.iter().enumerate() {
+        if let Some([next_stop, _]) = departures.first() {
+            check(format!("graph_pt_additions[{}][0][0]", ix), *next_stop as i64);
+        }
+    }
+    for (ix, node) in edits.graph_walk_updates_keys.iter().enumerate() {
+        check(format!("graph_walk_updates_keys[{}]", ix), *node as i64);
+    }
+    for (ix, edges) in edits.graph_walk_updates_additions.iter().enumerate() {
+        for (edge_ix, [_, to]) in edges.iter().enumerate() {
+            check(
+                format!("graph_walk_updates_additions[{}][{}][1]", ix, edge_ix),
+                *to as i64,
+            );
+        }
+    }
+    for (ix, new_build) in edits.new_build_additions.iter().enumerate() {
+        if let Some(node) = new_build.get(1) {
+            check(format!("new_build_additions[{}][1]", ix), *node as i64);
+        }
+    }
+    for (ix, change) in edits.node_value_changes.iter().enumerate() {
+        check(format!("node_value_changes[{}].node", ix), change.node() as i64);
+        if let NodeValueChange::Relocate { to_node, .. } = change {
+            check(format!("node_value_changes[{}].to_node", ix), *to_node as i64);
+        }
+    }
+    for (ix, [from, to]) in edits.graph_walk_removals.iter().enumerate() {
+        check(format!("graph_walk_removals[{}][0]", ix), *from as i64);
+        check(format!("graph_walk_removals[{}][1]", ix), *to as i64);
+    }
+    for (ix, node) in edits.disabled_nodes.iter().enumerate() {
+        check(format!("disabled_nodes[{}]", ix), *node as i64);
+    }
+    for (change_ix, change) in edits.pt_service_changes.iter().enumerate() {
+        for (ix, stop) in change.stops.iter().enumerate() {
+            check(format!("pt_service_changes[{}].stops[{}]", change_ix, ix), *stop as i64);
+        }
+    }
+    for (ix, extra) in edits.extra_departures.iter().enumerate() {
+        check(format!("extra_departures[{}].stop", ix), extra.stop as i64);
+        check(format!("extra_departures[{}].next_stop", ix), extra.next_stop as i64);
+    }
+    for (route_ix, route) in edits.new_routes.iter().enumerate() {
+        for (ix, stop) in route.stops.iter().enumerate() {
+            match stop {
+                RouteStop::Existing(node) => {
+                    check(format!("new_routes[{}].stops[{}]", route_ix, ix), *node as i64);
+                }
+                RouteStop::New { walk_links } => {
+                    for (link_ix, [_, node]) in walk_links.iter().enumerate() {
+                        check(
+                            format!(
+                                "new_routes[{}].stops[{}].walk_links[{}][1]",
+                                route_ix, ix, link_ix
+                            ),
+                            *node as i64,
+                        );
+                    }
+                }
+            }
+        }
+    }
+    for (ix, removal) in edits.pt_departure_removals.iter().enumerate() {
+        check(format!("pt_departure_removals[{}].stop", ix), removal.stop as i64);
+        if let Some(next_stop) = removal.next_stop {
+            check(format!("pt_departure_removals[{}].next_stop", ix), next_stop as i64);
+        }
+    }
+}
+
+fn check_node_id(
+    field: String,
+    node: i64,
+    node_count: usize,
+    year: i32,
+    problems: &mut Vec<FieldError>,
+) {
+    if node < 0 || node >= node_count as i64 {
+        problems.push(FieldError::new(
+            field,
+            format!(
+                "node {} doesn't exist in {}'s network, which has {} nodes including new ones",
+                node, year, node_count
+            ),
+        ));
+    }
+}
+
+/// Checks a request's or scenario's edits, adding any problems found
+fn validate_edits(edits: &ScenarioEdits, year: i32, problems: &mut Vec<FieldError>) {
+    if year < 2022 && !edits.graph_walk_additions.is_empty() {
+        problems.push(FieldError::new(
+            "graph_walk_additions",
+            "new nodes aren't supported for years before 2022",
+        ));
+    }
+    for (field, len) in [
+        ("graph_walk_additions", edits.graph_walk_additions.len()),
+        ("graph_pt_additions", edits.graph_pt_additions.len()),
+    ] {
+        if len != edits.new_nodes_count {
+            problems.push(FieldError::new(
+                field,
+                format!(
+                    "has {} entries but new_nodes_count is {}",
+                    len, edits.new_nodes_count
+                ),
+            ));
+        }
+    }
+    if edits.graph_walk_updates_additions.len() != edits.graph_walk_updates_keys.len() {
+        problems.push(FieldError::new(
+            "graph_walk_updates_additions",
+            format!(
+                "has {} entries but graph_walk_updates_keys has {}",
+                edits.graph_walk_updates_additions.len(),
+                edits.graph_walk_updates_keys.len()
+            ),
+        ));
+    }
+    for (field, edges_per_node) in [
+        ("graph_walk_additions", &edits.graph_walk_additions),
+        ("graph_walk_updates_additions", &edits.graph_walk_updates_additions),
+    ] {
+        for (ix, edges) in edges_per_node.iter().enumerate() {
+            for (edge_ix, [cost, _]) in edges.iter().enumerate() {
+                if *cost > u16::MAX as usize {
+                    problems.push(FieldError::new(
+                        format!("{}[{}][{}][0]", field, ix, edge_ix),
+                        format!("walk times can't be over {} seconds", u16::MAX),
+                    ));
+                }
+            }
+        }
+    }
+    for (ix, edges) in edits.graph_walk_additions.iter().enumerate() {
+        if edges.is_empty() {
+            problems.push(FieldError::new(
+                format!("graph_walk_additions[{}]", ix),
+                "needs at least the PT flag edge, [1, 0] or [0, 0]",
+            ));
+        }
+    }
+    for (ix, departures) in edits.graph_pt_additions.iter().enumerate() {
+        for (departure_ix, [_, cost]) in departures.iter().enumerate().skip(1) {
+            if *cost > u16::MAX as usize {
+                problems.push(FieldError::new(
+                    format!("graph_pt_additions[{}][{}][1]", ix, departure_ix),
+                    format!("journey times can't be over {} seconds", u16::MAX),
+                ));
+            }
+        }
+    }
+    for (ix, new_build) in edits.new_build_additions.iter().enumerate() {
+        if new_build.len() != 3 {
+            problems.push(FieldError::new(
+                format!("new_build_additions[{}]", ix),
+                "must be [value, nearest node ID, subpurpose index]",
+            ));
+            continue;
+        }
+        if new_build[1] < 0 {
+            problems.push(FieldError::new(
+                format!("new_build_additions[{}][1]", ix),
+                "node IDs can't be negative",
+            ));
+        }
+        if !(0..32).contains(&new_build[2]) {
+            problems.push(FieldError::new(
+                format!("new_build_additions[{}][2]", ix),
+                "subpurpose index must be between 0 and 31",
+            ));
+        }
+    }
+    for (ix, change) in edits.node_value_changes.iter().enumerate() {
+        validate_node_value_change(change, ix, problems);
+    }
+    for (ix, route) in edits.new_routes.iter().enumerate() {
+        validate_new_route(route, ix, year, problems);
+    }
+    for (ix, change) in edits.pt_service_changes.iter().enumerate() {
+        validate_pt_service_change(change, ix, problems);
+    }
+    for (ix, extra) in edits.extra_departures.iter().enumerate() {
+        if extra.stop == extra.next_stop {
+            problems.push(FieldError::new(
+                format!("extra_departures[{}].next_stop", ix),
+                "can't be the same as stop",
+            ));
+        }
+    }
+    for (ix, removal) in edits.pt_departure_removals.iter().enumerate() {
+        if removal.end_seconds <= removal.start_seconds {
+            problems.push(FieldError::new(
+                format!("pt_departure_removals[{}].end_seconds", ix),
+                "must be after start_seconds",
+            ));
+        }
+    }
+}
+
+fn validate_node_value_change(
+    change: &NodeValueChange,
+    change_ix: usize,
+    problems: &mut Vec<FieldError>,
+) {
+    let field = |name: &str| format!("node_value_changes[{}].{}", change_ix, name);
+    if let Some(subpurpose) = change.subpurpose() {
+        if subpurpose >= 32 {
+            problems.push(FieldError::new(
+                field("subpurpose"),
+                "subpurpose index must be between 0 and 31",
+            ));
+        }
+    }
+    match change {
+        NodeValueChange::Set { value, .. } if *value < 0 => {
+            problems.push(FieldError::new(field("value"), "can't be negative"));
+        }
+        NodeValueChange::Scale { multiplier, .. }
+            if !(*multiplier >= 0.0 && multiplier.is_finite()) =>
+        {
+            problems.push(FieldError::new(field("multiplier"), "can't be negative"));
+        }
+        NodeValueChange::Relocate { node, to_node, .. } if node == to_node => {
+            problems.push(FieldError::new(field("to_node"), "can't be the same as node"));
+        }
+        _ => {}
+    }
+}
+
+fn validate_pt_service_change(
+    change: &PTServiceChange,
+    change_ix: usize,
+    problems: &mut Vec<FieldError>,
+) {
+    let field = |name: &str| format!("pt_service_changes[{}].{}", change_ix, name);
+    if change.stops.is_empty() {
+        problems.push(FieldError::new(field("stops"), "needs at least 1 stop"));
+    }
+    if let (Some(start), Some(end)) = (change.start_seconds, change.end_seconds) {
+        if end <= start {
+            problems.push(FieldError::new(field("end_seconds"), "must be after start_seconds"));
+        }
+    }
+    if change.frequency_multiplier.is_none() && change.run_time_multiplier.is_none() {
+        problems.push(FieldError::new(
+            field("frequency_multiplier"),
+            "give frequency_multiplier, run_time_multiplier or both",
+        ));
+    }
+    // Caps the number of departures a scenario can add
+    if let Some(multiplier) = change.frequency_multiplier {
+        if !(multiplier > 0.0 && multiplier <= 10.0) {
+            problems.push(FieldError::new(
+                field("frequency_multiplier"),
+                "must be more than 0 and at most 10",
+            ));
+        }
+    }
+    if let Some(multiplier) = change.run_time_multiplier {
+        if !(multiplier > 0.0 && multiplier <= 10.0) {
+            problems.push(FieldError::new(
+                field("run_time_multiplier"),
+                "must be more than 0 and at most 10",
+            ));
+        }
+    }
+}
+
+fn validate_new_route(
+    route: &NewRoute,
+    route_ix: usize,
+    year: i32,
+    problems: &mut Vec<FieldError>,
+) {
+    let field = |name: &str| format!("new_routes[{}].{}", route_ix, name);
+    if route.stops.len() < 2 {
+        problems.push(FieldError::new(field("stops"), "needs at least 2 stops"));
+    }
+    if route.run_times_seconds.len() + 1 != route.stops.len() {
+        problems.push(FieldError::new(
+            field("run_times_seconds"),
+            format!(
+                "has {} entries but there are {} stops, so it needs {}",
+                route.run_times_seconds.len(),
+                route.stops.len(),
+                route.stops.len().saturating_sub(1)
+            ),
+        ));
+    }
+    for (ix, stop) in route.stops.iter().enumerate() {
+        if let RouteStop::New { walk_links } = stop {
+            if year < 2022 {
+                problems.push(FieldError::new(
+                    field(&format!("stops[{}]", ix)),
+                    "new nodes aren't supported for years before 2022",
+                ));
+            }
+            for (link_ix, [cost, _]) in walk_links.iter().enumerate() {
+                if *cost > u16::MAX as usize {
+                    problems.push(FieldError::new(
+                        field(&format!("stops[{}].walk_links[{}][0]", ix, link_ix)),
+                        format!("walk times can't be over {} seconds", u16::MAX),
+                    ));
+                }
+            }
+        }
+    }
+
+    let headway_fields = [
+        route.first_departure_seconds,
+        route.last_departure_seconds,
+        route.headway_seconds,
+    ];
+    let headway_fields_given = headway_fields.iter().filter(|value| value.is_some()).count();
+    match (headway_fields_given, route.departure_times_seconds.is_empty()) {
+        (3, true) => {
+            if route.headway_seconds == Some(0) {
+                problems.push(FieldError::new(field("headway_seconds"), "must be positive"));
+            }
+            if route.last_departure_seconds < route.first_departure_seconds {
+                problems.push(FieldError::new(
+                    field("last_departure_seconds"),
+                    "can't be before first_departure_seconds",
+                ));
+            }
+            if let [Some(first), Some(last), Some(headway)] = headway_fields {
+                if headway > 0 && last >= first {
+                    let departures = (last - first) as u64 / headway as u64 + 1;
+                    check_new_route_departures(departures, field("headway_seconds"), problems);
+                }
+            }
+        }
+        (0, false) => check_new_route_departures(
+            route.departure_times_seconds.len() as u64,
+            field("departure_times_seconds"),
+            problems,
+        ),
+        (0, true) => problems.push(FieldError::new(
+            field("departure_times_seconds"),
+            "give either departure times, or first_departure_seconds, last_departure_seconds and \
+             headway_seconds",
+        )),
+        (_, true) => problems.push(FieldError::new(
+            field("headway_seconds"),
+            "first_departure_seconds, last_departure_seconds and headway_seconds all need giving",
+        )),
+        (_, false) => problems.push(FieldError::new(
+            field("departure_times_seconds"),
+            "can't be combined with a headway",
+        )),
+    }
+}
+
+fn check_new_route_departures(departures: u64, field: String, problems: &mut Vec<FieldError>) {
+    if departures > MAX_NEW_ROUTE_DEPARTURES {
+        problems.push(FieldError::new(
+            field,
+            format!(
+                "gives {} departures, at most {} are allowed",
+                departures, MAX_NEW_ROUTE_DEPARTURES
+            ),
+        ));
+    }
+}
+
+fn into_result(problems: Vec<FieldError>) -> Result<(), ConnectivityError> {
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(ConnectivityError::InvalidRequest(problems))
+    }
+}